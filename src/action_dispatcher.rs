@@ -4,25 +4,24 @@ use evdev::{uinput::VirtualDevice, EventType, InputEvent, KeyCode as Key};
 use fork::{fork, setsid, Fork};
 use log::debug;
 use log::error;
-use nix::sys::signal;
-use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet};
 use std::process::{exit, Command, Stdio};
 
 use crate::event::RelativeEvent;
+use crate::supervisor::LaunchedSessions;
 use crate::{action::Action, event::KeyEvent};
 
 pub struct ActionDispatcher {
     // Device to emit events
     device: VirtualDevice,
-    // Whether we've called a sigaction for spawing commands or not
-    sigaction_set: bool,
+    // Sessions of the commands run by `launch`, for the supervisor to tell them apart
+    launched_sessions: LaunchedSessions,
 }
 
 impl ActionDispatcher {
-    pub fn new(device: VirtualDevice) -> ActionDispatcher {
+    pub fn new(device: VirtualDevice, launched_sessions: LaunchedSessions) -> ActionDispatcher {
         ActionDispatcher {
             device,
-            sigaction_set: false,
+            launched_sessions,
         }
     }
 
@@ -85,23 +84,16 @@ impl ActionDispatcher {
     }
 
     fn run_command(&mut self, command: Vec<String>) {
-        if !self.sigaction_set {
-            // Avoid defunct processes
-            let sig_action = SigAction::new(SigHandler::SigDfl, SaFlags::SA_NOCLDWAIT, SigSet::empty());
-            unsafe {
-                sigaction(signal::SIGCHLD, &sig_action).expect("Failed to register SIGCHLD handler");
-            }
-            self.sigaction_set = true;
-        }
-
         debug!("Running command: {:?}", command);
         match fork() {
             Ok(Fork::Child) => {
+                // Start a new session whose id is this child's pid, so that the supervisor can tell
+                // the command apart from descendants of the wrapped command once xremap adopts it.
+                setsid().expect("Failed to setsid.");
                 // Child process should fork again, and the parent should exit 0, while the child
                 // should spawn the user command then exit as well.
                 match fork() {
                     Ok(Fork::Child) => {
-                        setsid().expect("Failed to setsid.");
                         match Command::new(&command[0])
                             .args(&command[1..])
                             .stdin(Stdio::null())
@@ -126,8 +118,8 @@ impl ActionDispatcher {
                     }
                 }
             }
-            // Parent should simply continue.
-            Ok(Fork::Parent(_)) => (),
+            // Parent should simply continue. Exited helpers are reaped by the supervisor.
+            Ok(Fork::Parent(pid)) => self.launched_sessions.register(pid),
             Err(e) => error!("Error spawning process: {:?}", e),
         }
     }
@@ -0,0 +1,152 @@
+use crate::client::Client;
+use nix::libc::c_int;
+use nix::sys::stat::{major, minor, stat};
+use procfs::process::{all_processes, Process};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+// The active virtual console, e.g. tty2
+const ACTIVE_CONSOLE: &str = "/sys/class/tty/tty0/active";
+
+// From linux/kd.h
+const KDGETMODE: u32 = 0x4B3B;
+const KD_TEXT: c_int = 0x00;
+
+nix::ioctl_read_bad!(kd_get_mode, KDGETMODE, c_int);
+
+// Reports the active Linux virtual console as the application,
+// and the foreground process of that console as the window.
+pub struct ConsoleClient {
+    // A process with the console as its controlling terminal, whose stat tells the foreground
+    // process group of the console without scanning all processes on every query
+    anchor: Option<Anchor>,
+}
+
+struct Anchor {
+    device: (i32, i32),
+    pid: i32,
+}
+
+impl ConsoleClient {
+    pub fn new() -> ConsoleClient {
+        ConsoleClient { anchor: None }
+    }
+
+    fn foreground_process(&mut self) -> Option<i32> {
+        let console = active_console()?;
+        let device = device_number(&format!("/dev/{console}"))?;
+        self.foreground_process_of(device)
+    }
+
+    // Process group leader of the foreground job of a terminal
+    fn foreground_process_of(&mut self, device: (i32, i32)) -> Option<i32> {
+        if let Some(anchor) = &self.anchor {
+            if anchor.device == device {
+                if let Ok(stat) = Process::new(anchor.pid).and_then(|process| process.stat()) {
+                    if stat.tty_nr() == device && stat.tpgid > 0 {
+                        return Some(stat.tpgid);
+                    }
+                }
+            }
+        }
+
+        for process in all_processes().ok()?.flatten() {
+            let Ok(stat) = process.stat() else {
+                continue;
+            };
+            if stat.tty_nr() == device && stat.tpgid > 0 {
+                // The session leader, e.g. the login shell, is likely to outlive the foreground job
+                let pid = if stat.session > 0 { stat.session } else { stat.pid };
+                self.anchor = Some(Anchor { device, pid });
+                return Some(stat.tpgid);
+            }
+        }
+        self.anchor = None;
+        None
+    }
+}
+
+impl Client for ConsoleClient {
+    fn supported(&mut self) -> bool {
+        active_console().is_some()
+    }
+
+    fn current_window(&mut self) -> Option<String> {
+        let process = Process::new(self.foreground_process()?).ok()?;
+        match process.cmdline() {
+            Ok(cmdline) if !cmdline.is_empty() => Some(cmdline.join(" ")),
+            _ => Some(process.stat().ok()?.comm),
+        }
+    }
+
+    fn current_application(&mut self) -> Option<String> {
+        active_console()
+    }
+
+    fn current_pid(&mut self) -> Option<u32> {
+        self.foreground_process().map(|pid| pid as u32)
+    }
+}
+
+fn active_console() -> Option<String> {
+    let console = std::fs::read_to_string(ACTIVE_CONSOLE).ok()?;
+    let console = console.trim();
+    if console.is_empty() {
+        return None;
+    }
+    Some(console.to_string())
+}
+
+// Major and minor numbers of a device, like Stat::tty_nr
+fn device_number(path: &str) -> Option<(i32, i32)> {
+    let rdev = stat(path).ok()?.st_rdev;
+    Some((major(rdev) as i32, minor(rdev) as i32))
+}
+
+// Whether the active virtual console shows text rather than graphics, e.g. of an X11 or Wayland session
+pub fn is_text_mode() -> bool {
+    let Ok(tty) = File::open("/dev/tty0") else {
+        return false;
+    };
+    let mut mode: c_int = 0;
+    unsafe { kd_get_mode(tty.as_raw_fd(), &mut mode) }.is_ok() && mode == KD_TEXT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::pty::openpty;
+    use nix::unistd::ttyname;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_foreground_process() {
+        // A session leader with the terminal as its controlling terminal
+        let pty = openpty(None, None).unwrap();
+        let device = device_number(ttyname(pty.slave).unwrap().to_str().unwrap()).unwrap();
+        let mut child = Command::new("sleep");
+        child.arg("10").stdin(unsafe { Stdio::from_raw_fd(pty.slave) });
+        unsafe {
+            child.pre_exec(|| {
+                nix::unistd::setsid()?;
+                nix::libc::ioctl(0, nix::libc::TIOCSCTTY, 0);
+                Ok(())
+            });
+        }
+        let mut child = child.spawn().unwrap();
+
+        let mut client = ConsoleClient::new();
+        assert_eq!(client.foreground_process_of(device), Some(child.id() as i32));
+        // Found again through the anchor
+        assert_eq!(client.anchor.as_ref().map(|anchor| anchor.pid), Some(child.id() as i32));
+        assert_eq!(client.foreground_process_of(device), Some(child.id() as i32));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        nix::unistd::close(pty.master).unwrap();
+        assert_eq!(client.foreground_process_of(device), None);
+        assert!(client.anchor.is_none());
+    }
+}
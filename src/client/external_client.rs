@@ -0,0 +1,311 @@
+use crate::client::{Client, WindowProperties};
+use anyhow::{bail, Context};
+use nix::sys::epoll::{epoll_create1, epoll_ctl, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp};
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+// Serves focus information written by another program, one JSON object per line, e.g.
+// {"application": "firefox", "title": "Mozilla Firefox", "pid": 1234, "workspace": "2", "fullscreen": true}
+// The path is a FIFO when one exists there, and a Unix socket created by xremap otherwise.
+pub struct ExternalClient {
+    path: PathBuf,
+    // Readable when the listener or any input is, so that a single fd can be selected on
+    epoll: Option<OwnedFd>,
+    listener: Option<UnixListener>,
+    inputs: Vec<Input>,
+    focus: FocusInfo,
+}
+
+struct Input {
+    file: File,
+    // An incomplete line
+    buffer: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+struct FocusInfo {
+    application: Option<String>,
+    title: Option<String>,
+    pid: Option<u32>,
+    workspace: Option<String>,
+    output: Option<String>,
+    fullscreen: bool,
+    floating: bool,
+    marks: Vec<String>,
+}
+
+impl ExternalClient {
+    pub fn new(path: PathBuf) -> ExternalClient {
+        ExternalClient {
+            path,
+            epoll: None,
+            listener: None,
+            inputs: vec![],
+            focus: FocusInfo::default(),
+        }
+    }
+
+    fn open(&mut self) -> anyhow::Result<()> {
+        let epoll = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)?;
+        self.epoll = Some(unsafe { OwnedFd::from_raw_fd(epoll) });
+
+        let file_type = std::fs::metadata(&self.path).map(|metadata| metadata.file_type());
+        if file_type.as_ref().is_ok_and(FileTypeExt::is_fifo) {
+            // Opened for writing too, so that it doesn't hit EOF whenever a writer closes it
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(nix::libc::O_NONBLOCK)
+                .open(&self.path)
+                .with_context(|| format!("Failed to open {}", self.path.display()))?;
+            self.add_input(file)?;
+            println!("Reading focus information from the FIFO {}", self.path.display());
+        } else {
+            if file_type.as_ref().is_ok_and(FileTypeExt::is_socket) {
+                if UnixStream::connect(&self.path).is_ok() {
+                    bail!("Another process is listening on {}", self.path.display());
+                }
+                // A socket left by a previous run
+                std::fs::remove_file(&self.path)?;
+            }
+            let listener = UnixListener::bind(&self.path)
+                .with_context(|| format!("Failed to create a socket at {}", self.path.display()))?;
+            listener.set_nonblocking(true)?;
+            self.watch(listener.as_raw_fd())?;
+            self.listener = Some(listener);
+            println!("Reading focus information from the socket {}", self.path.display());
+        }
+        Ok(())
+    }
+
+    fn watch(&self, fd: RawFd) -> nix::Result<()> {
+        let Some(epoll) = &self.epoll else {
+            return Ok(());
+        };
+        let mut event = EpollEvent::new(EpollFlags::EPOLLIN, fd as u64);
+        epoll_ctl(epoll.as_raw_fd(), EpollOp::EpollCtlAdd, fd, &mut event)
+    }
+
+    fn add_input(&mut self, file: File) -> nix::Result<()> {
+        self.watch(file.as_raw_fd())?;
+        self.inputs.push(Input { file, buffer: vec![] });
+        Ok(())
+    }
+
+    fn accept(&mut self) {
+        let Some(listener) = &self.listener else {
+            return;
+        };
+        let mut streams = vec![];
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => streams.push(stream),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("Failed to accept a connection on {}: {e}", self.path.display());
+                    break;
+                }
+            }
+        }
+        for stream in streams {
+            if let Err(e) = stream.set_nonblocking(true) {
+                println!("Failed to accept a connection on {}: {e}", self.path.display());
+                continue;
+            }
+            if let Err(e) = self.add_input(File::from(OwnedFd::from(stream))) {
+                println!("Failed to accept a connection on {}: {e}", self.path.display());
+            }
+        }
+    }
+
+    // Read every input until it would block, returning complete lines.
+    // Inputs are closed once their writer is gone, which is never the case for a FIFO opened for writing.
+    fn read_lines(&mut self) -> Vec<String> {
+        let mut lines = vec![];
+        self.inputs.retain_mut(|input| {
+            let mut buf = [0; 4096];
+            let open = loop {
+                match input.file.read(&mut buf) {
+                    Ok(0) => break false,
+                    Ok(n) => input.buffer.extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break true,
+                    Err(e) => {
+                        println!("Failed to read focus information: {e}");
+                        break false;
+                    }
+                }
+            };
+            if let Some(end) = input.buffer.iter().rposition(|&byte| byte == b'\n') {
+                let complete: Vec<u8> = input.buffer.drain(..=end).collect();
+                lines.extend(String::from_utf8_lossy(&complete).lines().map(String::from));
+            }
+            open
+        });
+        lines
+    }
+}
+
+impl Drop for ExternalClient {
+    fn drop(&mut self) {
+        if self.listener.is_some() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Client for ExternalClient {
+    fn supported(&mut self) -> bool {
+        if let Err(e) = self.open() {
+            println!("{e:#}");
+            return false;
+        }
+        true
+    }
+
+    fn current_window(&mut self) -> Option<String> {
+        self.focus.title.clone()
+    }
+
+    fn current_application(&mut self) -> Option<String> {
+        self.focus.application.clone()
+    }
+
+    fn current_pid(&mut self) -> Option<u32> {
+        self.focus.pid
+    }
+
+    fn current_window_properties(&mut self) -> Option<WindowProperties> {
+        Some(WindowProperties {
+            workspace: self.focus.workspace.clone(),
+            output: self.focus.output.clone(),
+            fullscreen: self.focus.fullscreen,
+            floating: self.focus.floating,
+            marks: self.focus.marks.clone(),
+        })
+    }
+
+    fn focus_fd(&mut self) -> Option<RawFd> {
+        self.epoll.as_ref().map(AsRawFd::as_raw_fd)
+    }
+
+    fn on_focus_fd_readable(&mut self) -> bool {
+        self.accept();
+        let mut changed = false;
+        for line in self.read_lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<FocusInfo>(&line) {
+                Ok(focus) => {
+                    changed |= focus != self.focus;
+                    self.focus = focus;
+                }
+                Err(e) => println!("Invalid focus information {line:?}: {e}"),
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::stat::Mode;
+    use nix::unistd::mkfifo;
+    use std::io::Write;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("xremap-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_socket() {
+        let path = temp_path("external-socket");
+        let mut client = ExternalClient::new(path.clone());
+        assert!(client.supported());
+        assert!(client.focus_fd().is_some());
+        assert_eq!(client.current_application(), None);
+
+        let mut writer = UnixStream::connect(&path).unwrap();
+        writer
+            .write_all(b"{\"application\":\"firefox\",\"title\":\"Mozilla Firefox\",\"pid\":1234}\n{\"applic")
+            .unwrap();
+        assert!(client.on_focus_fd_readable());
+        assert_eq!(client.current_application(), Some("firefox".to_string()));
+        assert_eq!(client.current_window(), Some("Mozilla Firefox".to_string()));
+        assert_eq!(client.current_pid(), Some(1234));
+
+        // The rest of a line, and a second writer
+        writer.write_all(b"ation\":\"kitty\"}\n").unwrap();
+        let mut other = UnixStream::connect(&path).unwrap();
+        other.write_all(b"not json\n{\"application\":\"kitty\"}\n").unwrap();
+        assert!(client.on_focus_fd_readable());
+        assert_eq!(client.current_application(), Some("kitty".to_string()));
+        assert_eq!(client.current_window(), None);
+        assert_eq!(client.current_pid(), None);
+        assert!(!client.on_focus_fd_readable());
+
+        other
+            .write_all(b"{\"application\":\"kitty\",\"workspace\":\"2\",\"fullscreen\":true,\"marks\":[\"scratch\"]}\n")
+            .unwrap();
+        assert!(client.on_focus_fd_readable());
+        let properties = client.current_window_properties().unwrap();
+        assert_eq!(properties.workspace, Some("2".to_string()));
+        assert_eq!(properties.output, None);
+        assert!(properties.fullscreen);
+        assert_eq!(properties.marks, vec!["scratch".to_string()]);
+
+        // Writers may come and go
+        drop(writer);
+        drop(other);
+        assert!(!client.on_focus_fd_readable());
+        assert!(client.inputs.is_empty());
+
+        drop(client);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_socket_in_use() {
+        let path = temp_path("external-socket-in-use");
+        let mut client = ExternalClient::new(path.clone());
+        assert!(client.supported());
+        assert!(!ExternalClient::new(path.clone()).supported());
+        assert!(path.exists());
+
+        // A socket without a listener is replaced
+        drop(client.listener.take());
+        drop(client);
+        assert!(path.exists());
+        assert!(ExternalClient::new(path.clone()).supported());
+    }
+
+    #[test]
+    fn test_fifo() {
+        let path = temp_path("external-fifo");
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        let mut client = ExternalClient::new(path.clone());
+        assert!(client.supported());
+
+        for application in ["firefox", "kitty"] {
+            let mut writer = OpenOptions::new().write(true).open(&path).unwrap();
+            writeln!(writer, r#"{{"application":"{application}"}}"#).unwrap();
+            drop(writer);
+            assert!(client.on_focus_fd_readable());
+            assert_eq!(client.current_application(), Some(application.to_string()));
+        }
+
+        drop(client);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
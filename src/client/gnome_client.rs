@@ -26,11 +26,24 @@ impl Client for GnomeClient {
         self.current_application().is_some()
     }
     fn current_window(&mut self) -> Option<String> {
-        // TODO:  not implemented
-        None
+        let window = self.active_window()?;
+        if window.title.is_empty() {
+            return None;
+        }
+        Some(window.title)
     }
 
     fn current_application(&mut self) -> Option<String> {
+        Some(self.active_window()?.wm_class)
+    }
+
+    fn current_pid(&mut self) -> Option<u32> {
+        self.active_window()?.pid
+    }
+}
+
+impl GnomeClient {
+    fn active_window(&mut self) -> Option<ActiveWindow> {
         self.connect();
         let connection = match &mut self.connection {
             Some(connection) => connection,
@@ -47,10 +60,10 @@ impl Client for GnomeClient {
         )) {
             if let Ok(json) = message.body().deserialize::<String>() {
                 if let Ok(window) = serde_json::from_str::<ActiveWindow>(&json) {
-                    return Some(window.wm_class);
+                    return Some(window);
                 }
             }
-        // Fallback to the legacy protocol
+        // Fallback to the legacy protocol, which only knows WM_CLASS
         } else if let Ok(message) = block_on(connection.call_method(
             Some("org.gnome.Shell"),
             "/com/k0kubun/Xremap",
@@ -59,7 +72,11 @@ impl Client for GnomeClient {
             &(),
         )) {
             if let Ok(wm_class) = message.body().deserialize::<String>() {
-                return Some(wm_class);
+                return Some(ActiveWindow {
+                    wm_class,
+                    title: String::new(),
+                    pid: None,
+                });
             }
         }
         None
@@ -72,4 +89,28 @@ struct ActiveWindow {
     wm_class: String,
     #[serde(default)]
     title: String,
+    // Not sent by older versions of the extension
+    #[serde(default)]
+    pid: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_active_window() {
+        let json = r#"{"wm_class":"firefox","title":"Mozilla Firefox","pid":1234}"#;
+        let window: ActiveWindow = serde_json::from_str(json).unwrap();
+        assert_eq!(window.wm_class, "firefox");
+        assert_eq!(window.title, "Mozilla Firefox");
+        assert_eq!(window.pid, Some(1234));
+    }
+
+    #[test]
+    fn test_active_window_without_pid() {
+        let window: ActiveWindow = serde_json::from_str(r#"{"wm_class":"firefox","title":""}"#).unwrap();
+        assert_eq!(window.title, "");
+        assert_eq!(window.pid, None);
+    }
 }
@@ -1,32 +1,369 @@
-use crate::client::Client;
-use hyprland::{data::Client as HyprClient, prelude::*};
-pub struct HyprlandClient;
+use crate::client::{Client, WindowProperties};
+use serde::Deserialize;
+use std::env;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+// Talks to Hyprland's IPC sockets directly: .socket.sock answers requests like `j/activewindow`,
+// and .socket2.sock streams events like `activewindowv2>>ADDRESS`, one per line.
+pub struct HyprlandClient {
+    socket_dir: Option<PathBuf>,
+    events: Option<UnixStream>,
+    // An incomplete event line read from the event socket
+    buffer: Vec<u8>,
+    // Refreshed when Hyprland reports a change of the active window
+    active_window: Option<ActiveWindow>,
+    // Name of the monitor of the active window
+    output: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ActiveWindow {
+    // Without the 0x prefix, as in events
+    #[serde(deserialize_with = "deserialize_address")]
+    address: String,
+    class: String,
+    title: String,
+    pid: i32,
+    workspace: Workspace,
+    // Monitor id
+    monitor: i64,
+    floating: bool,
+    #[serde(deserialize_with = "deserialize_fullscreen")]
+    fullscreen: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Workspace {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Monitor {
+    id: i64,
+    name: String,
+}
+
+fn deserialize_address<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let address = String::deserialize(deserializer)?;
+    Ok(address.trim_start_matches("0x").to_string())
+}
+
+// A bool before Hyprland 0.42, and a mode since then: 0 none, 1 maximized, 2 fullscreen, 3 both
+fn deserialize_fullscreen<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Fullscreen {
+        State(bool),
+        Mode(u64),
+    }
+    Ok(match Fullscreen::deserialize(deserializer)? {
+        Fullscreen::State(fullscreen) => fullscreen,
+        Fullscreen::Mode(mode) => mode & 2 != 0,
+    })
+}
 
 impl HyprlandClient {
     pub fn new() -> HyprlandClient {
-        HyprlandClient {}
+        HyprlandClient::with_socket_dir(socket_dir())
+    }
+
+    fn with_socket_dir(socket_dir: Option<PathBuf>) -> HyprlandClient {
+        HyprlandClient {
+            socket_dir,
+            events: None,
+            buffer: vec![],
+            active_window: None,
+            output: None,
+        }
+    }
+
+    fn connect(&mut self) -> anyhow::Result<()> {
+        let Some(socket_dir) = &self.socket_dir else {
+            anyhow::bail!("$HYPRLAND_INSTANCE_SIGNATURE is not set");
+        };
+        let events = UnixStream::connect(socket_dir.join(".socket2.sock"))?;
+        events.set_nonblocking(true)?;
+        self.events = Some(events);
+        self.buffer.clear();
+        // Events only report changes, so start from the currently active window
+        self.refresh();
+        Ok(())
+    }
+
+    fn refresh(&mut self) {
+        self.active_window = self.request_active_window();
+        // Windows only know the id of their monitor
+        self.output = self.active_window.as_ref().and_then(|window| {
+            let monitors: Vec<Monitor> = self.request("j/monitors")?;
+            let monitor = monitors.into_iter().find(|monitor| monitor.id == window.monitor)?;
+            Some(monitor.name)
+        });
+    }
+
+    fn request_active_window(&self) -> Option<ActiveWindow> {
+        // `{}` when no window is focused
+        let window: ActiveWindow = self.request("j/activewindow")?;
+        if window.address.is_empty() {
+            return None;
+        }
+        log::debug!("Hyprland active window: {window:?}");
+        Some(window)
+    }
+
+    fn request<T: serde::de::DeserializeOwned>(&self, command: &str) -> Option<T> {
+        let mut stream = UnixStream::connect(self.socket_dir.as_ref()?.join(".socket.sock")).ok()?;
+        stream.write_all(command.as_bytes()).ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok()?;
+        serde_json::from_str(&response).ok()
+    }
+
+    // Read the event socket until it would block, returning complete lines
+    fn read_events(&mut self) -> Vec<String> {
+        let Some(events) = &mut self.events else {
+            return vec![];
+        };
+        let mut buf = [0; 4096];
+        loop {
+            match events.read(&mut buf) {
+                Ok(0) => {
+                    println!("Hyprland closed its event socket");
+                    self.events = None;
+                    self.active_window = None;
+                    self.output = None;
+                    break;
+                }
+                Ok(n) => self.buffer.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("Failed to read Hyprland events: {e}");
+                    self.events = None;
+                    self.active_window = None;
+                    self.output = None;
+                    break;
+                }
+            }
+        }
+
+        let Some(end) = self.buffer.iter().rposition(|&byte| byte == b'\n') else {
+            return vec![];
+        };
+        let lines: Vec<u8> = self.buffer.drain(..=end).collect();
+        String::from_utf8_lossy(&lines).lines().map(String::from).collect()
+    }
+
+    fn is_active(&self, address: &str) -> bool {
+        self.active_window
+            .as_ref()
+            .is_some_and(|window| window.address == address)
     }
 }
 
 impl Client for HyprlandClient {
     fn supported(&mut self) -> bool {
+        if let Err(e) = self.connect() {
+            println!("Failed to connect to Hyprland: {e}");
+            return false;
+        }
         true
     }
+
     fn current_window(&mut self) -> Option<String> {
-        if let Ok(win_opt) = HyprClient::get_active() {
-            if let Some(win) = win_opt {
-                return Some(win.title);
+        Some(self.active_window.as_ref()?.title.clone())
+    }
+
+    fn current_application(&mut self) -> Option<String> {
+        Some(self.active_window.as_ref()?.class.clone())
+    }
+
+    fn current_pid(&mut self) -> Option<u32> {
+        u32::try_from(self.active_window.as_ref()?.pid)
+            .ok()
+            .filter(|&pid| pid > 0)
+    }
+
+    fn current_window_properties(&mut self) -> Option<WindowProperties> {
+        let window = self.active_window.as_ref()?;
+        Some(WindowProperties {
+            workspace: Some(window.workspace.name.clone()),
+            output: self.output.clone(),
+            fullscreen: window.fullscreen,
+            floating: window.floating,
+            ..Default::default()
+        })
+    }
+
+    // Reconnects once the event socket was closed, e.g. when Hyprland restarted
+    fn focus_fd(&mut self) -> Option<RawFd> {
+        if self.events.is_none() {
+            match self.connect() {
+                Ok(()) => println!("Reconnected to Hyprland"),
+                Err(e) => log::debug!("Failed to reconnect to Hyprland: {e}"),
             }
         }
-        None
+        self.events.as_ref().map(AsRawFd::as_raw_fd)
     }
 
-    fn current_application(&mut self) -> Option<String> {
-        if let Ok(win_opt) = HyprClient::get_active() {
-            if let Some(win) = win_opt {
-                return Some(win.class);
+    fn on_focus_fd_readable(&mut self) -> bool {
+        let disconnected = self.events.is_none();
+        let mut changed = false;
+        for line in self.read_events() {
+            let Some((event, data)) = line.split_once(">>") else {
+                continue;
+            };
+            let address = data.split(',').next().unwrap_or_default();
+            let refresh = match event {
+                // activewindowv2>>ADDRESS, or activewindowv2>>, when no window is focused
+                "activewindowv2" => address.is_empty() || !self.is_active(address),
+                // windowtitlev2>>ADDRESS,TITLE (windowtitle>>ADDRESS before Hyprland 0.42)
+                "windowtitle" | "windowtitlev2" => self.is_active(address),
+                // movewindowv2>>ADDRESS,WORKSPACEID,WORKSPACENAME
+                "movewindowv2" => self.is_active(address),
+                // changefloatingmode>>ADDRESS,FLOATING
+                "changefloatingmode" => self.is_active(address),
+                // fullscreen>>STATE, for the active window
+                "fullscreen" => true,
+                // moveworkspacev2>>WORKSPACEID,WORKSPACENAME,MONITOR and renameworkspace>>WORKSPACEID,NAME
+                "moveworkspacev2" | "renameworkspace" => self.active_window.is_some(),
+                _ => false,
+            };
+            if refresh {
+                self.refresh();
+                changed = true;
             }
         }
-        None
+        changed || (self.events.is_none() && !disconnected)
+    }
+}
+
+// $XDG_RUNTIME_DIR/hypr/$HYPRLAND_INSTANCE_SIGNATURE, or /tmp/hypr before Hyprland 0.40
+fn socket_dir() -> Option<PathBuf> {
+    let signature = env::var("HYPRLAND_INSTANCE_SIGNATURE").ok()?;
+    if let Ok(runtime_dir) = env::var("XDG_RUNTIME_DIR") {
+        let socket_dir = PathBuf::from(runtime_dir).join("hypr").join(&signature);
+        if socket_dir.exists() {
+            return Some(socket_dir);
+        }
+    }
+    Some(PathBuf::from("/tmp/hypr").join(signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // Answers j/activewindow with the JSON in `active_window`, and j/monitors, like Hyprland's .socket.sock
+    fn serve_requests(listener: UnixListener, active_window: Arc<Mutex<String>>) {
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 64];
+                let n = stream.read(&mut request).unwrap();
+                let response = match &request[..n] {
+                    b"j/activewindow" => active_window.lock().unwrap().clone(),
+                    b"j/monitors" => r#"[{"id":0,"name":"eDP-1"},{"id":1,"name":"HDMI-A-1"}]"#.to_string(),
+                    request => panic!("unexpected request {:?}", String::from_utf8_lossy(request)),
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+    }
+
+    fn window_json(address: &str, class: &str, title: &str, pid: i32) -> String {
+        format!(
+            r#"{{"address":"0x{address}","class":"{class}","title":"{title}","pid":{pid},"workspace":{{"id":1,"name":"1"}},"monitor":0,"floating":false,"fullscreen":0}}"#
+        )
+    }
+
+    #[test]
+    fn test_active_window_events() {
+        let socket_dir = env::temp_dir().join(format!("xremap-hypr-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&socket_dir);
+        std::fs::create_dir_all(&socket_dir).unwrap();
+        let active_window = Arc::new(Mutex::new(window_json("a1", "kitty", "~", 100)));
+        serve_requests(UnixListener::bind(socket_dir.join(".socket.sock")).unwrap(), active_window.clone());
+        let events_listener = UnixListener::bind(socket_dir.join(".socket2.sock")).unwrap();
+
+        let mut client = HyprlandClient::with_socket_dir(Some(socket_dir.clone()));
+        assert!(client.supported());
+        let (mut events, _) = events_listener.accept().unwrap();
+        assert!(client.focus_fd().is_some());
+        assert_eq!(client.current_application(), Some("kitty".to_string()));
+        assert_eq!(client.current_pid(), Some(100));
+
+        // Focus moves to another window, and the event arrives in two reads
+        *active_window.lock().unwrap() = window_json("b2", "firefox", "Mozilla Firefox", 200);
+        events
+            .write_all(b"workspacev2>>1,1\nactivewindow>>firefox,Mozilla Firefox\nactivewind")
+            .unwrap();
+        assert!(!client.on_focus_fd_readable());
+        assert_eq!(client.current_application(), Some("kitty".to_string()));
+        events.write_all(b"owv2>>b2\n").unwrap();
+        assert!(client.on_focus_fd_readable());
+        assert_eq!(client.current_application(), Some("firefox".to_string()));
+        assert_eq!(client.current_window(), Some("Mozilla Firefox".to_string()));
+        assert_eq!(client.current_pid(), Some(200));
+
+        // Only title changes of the active window matter
+        events.write_all(b"windowtitlev2>>a1,vim\n").unwrap();
+        assert!(!client.on_focus_fd_readable());
+        *active_window.lock().unwrap() = window_json("b2", "firefox", "GitHub", 200);
+        events.write_all(b"windowtitlev2>>b2,GitHub\n").unwrap();
+        assert!(client.on_focus_fd_readable());
+        assert_eq!(client.current_window(), Some("GitHub".to_string()));
+
+        // The active window goes fullscreen on another monitor
+        let properties = client.current_window_properties().unwrap();
+        assert_eq!(properties.workspace, Some("1".to_string()));
+        assert_eq!(properties.output, Some("eDP-1".to_string()));
+        assert!(!properties.fullscreen);
+        *active_window.lock().unwrap() = window_json("b2", "firefox", "GitHub", 200)
+            .replace(r#""monitor":0"#, r#""monitor":1"#)
+            .replace(r#""fullscreen":0"#, r#""fullscreen":2"#);
+        events.write_all(b"fullscreen>>1\n").unwrap();
+        assert!(client.on_focus_fd_readable());
+        let properties = client.current_window_properties().unwrap();
+        assert_eq!(properties.output, Some("HDMI-A-1".to_string()));
+        assert!(properties.fullscreen);
+
+        // No window is focused
+        *active_window.lock().unwrap() = "{}".to_string();
+        events.write_all(b"activewindowv2>>\n").unwrap();
+        assert!(client.on_focus_fd_readable());
+        assert_eq!(client.current_application(), None);
+        assert_eq!(client.current_pid(), None);
+        assert_eq!(client.current_window_properties(), None);
+
+        // Hyprland closes the event socket, and is reconnected to
+        *active_window.lock().unwrap() = window_json("a1", "kitty", "~", 100);
+        drop(events);
+        assert!(client.on_focus_fd_readable());
+        assert_eq!(client.current_application(), None);
+        assert!(client.focus_fd().is_some());
+        let (events, _) = events_listener.accept().unwrap();
+        assert_eq!(client.current_application(), Some("kitty".to_string()));
+
+        // Hyprland exits
+        drop(events_listener);
+        drop(events);
+        assert!(client.on_focus_fd_readable());
+        assert!(client.focus_fd().is_none());
+
+        std::fs::remove_dir_all(&socket_dir).unwrap();
     }
 }
@@ -1,7 +1,9 @@
 use futures::executor::block_on;
 use log::{debug, warn};
+use procfs::process::Process;
 use std::env::temp_dir;
 use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -9,23 +11,53 @@ use std::time::Duration;
 use zbus::connection::Builder;
 use zbus::{interface, Connection};
 
-use crate::client::Client;
+use crate::client::{Client, WindowProperties};
 
 const KWIN_SCRIPT: &str = include_str!("kwin-script.js");
-const KWIN_SCRIPT_PLUGIN_NAME: &str = "xremap";
+// The D-Bus name in kwin-script.js, which each instance replaces with its own
+const KWIN_SCRIPT_BUS_NAME: &str = "com.k0kubun.Xremap";
 
 pub struct KdeClient {
     active_window: Arc<Mutex<ActiveWindow>>,
+    // The kwin-script of this instance, to be unloaded on exit. Its file is kept meanwhile, so that
+    // the scripts of instances that didn't exit cleanly can be found.
+    script_file: Option<KwinScriptTempFile>,
+}
+
+// Names are per process, so that several instances of xremap can each have their own kwin-script
+fn plugin_name() -> String {
+    plugin_name_of(process::id())
+}
+
+fn plugin_name_of(pid: u32) -> String {
+    format!("xremap-{pid}")
+}
+
+fn bus_name() -> String {
+    format!("{KWIN_SCRIPT_BUS_NAME}.Instance{}", process::id())
+}
+
+fn kwin_script(bus_name: &str) -> String {
+    KWIN_SCRIPT.replacen(&format!("\"{KWIN_SCRIPT_BUS_NAME}\""), &format!("\"{bus_name}\""), 1)
 }
 
 struct KwinScriptTempFile(PathBuf);
 
 impl KwinScriptTempFile {
     fn new() -> Self {
-        Self(temp_dir().join("xremap-kwin-script.js"))
+        Self(temp_dir().join(format!("{}-kwin-script.js", plugin_name())))
     }
 }
 
+// The pid of the instance that wrote a kwin-script file, from its name
+fn script_file_pid(file_name: &str) -> Option<u32> {
+    file_name
+        .strip_prefix("xremap-")?
+        .strip_suffix("-kwin-script.js")?
+        .parse()
+        .ok()
+}
+
 impl Drop for KwinScriptTempFile {
     fn drop(&mut self) {
         let _ = std::fs::remove_file(&self.0);
@@ -34,7 +66,7 @@ impl Drop for KwinScriptTempFile {
 
 trait KWinScripting {
     fn load_script(&self, path: &Path) -> Result<i32, ConnectionError>;
-    fn unload_script(&self) -> Result<bool, ConnectionError>;
+    fn unload_script(&self, plugin_name: &str) -> Result<bool, ConnectionError>;
     fn start_script(&self, script_obj_id: i32) -> Result<(), ConnectionError>;
     fn is_script_loaded(&self) -> Result<bool, ConnectionError>;
 }
@@ -47,7 +79,7 @@ impl KWinScripting for Connection {
             Some("org.kde.kwin.Scripting"),
             "loadScript",
             // since OsStr does not implement zvariant::Type, the temp-path must be valid utf-8
-            &(path.to_str().ok_or(ConnectionError::TempPathNotValidUtf8)?, KWIN_SCRIPT_PLUGIN_NAME),
+            &(path.to_str().ok_or(ConnectionError::TempPathNotValidUtf8)?, plugin_name()),
         ))
         .map_err(|_| ConnectionError::LoadScriptCall)?
         .body()
@@ -55,14 +87,13 @@ impl KWinScripting for Connection {
         .map_err(|_| ConnectionError::InvalidLoadScriptResult)
     }
 
-    fn unload_script(&self) -> Result<bool, ConnectionError> {
+    fn unload_script(&self, plugin_name: &str) -> Result<bool, ConnectionError> {
         block_on(self.call_method(
             Some("org.kde.KWin"),
             "/Scripting",
             Some("org.kde.kwin.Scripting"),
             "unloadScript",
-            // since OsStr does not implement zvariant::Type, the temp-path must be valid utf-8
-            &KWIN_SCRIPT_PLUGIN_NAME,
+            &plugin_name,
         ))
         .map_err(|_| ConnectionError::UnloadScriptCall)?
         .body()
@@ -93,7 +124,7 @@ impl KWinScripting for Connection {
             "/Scripting",
             Some("org.kde.kwin.Scripting"),
             "isScriptLoaded",
-            &KWIN_SCRIPT_PLUGIN_NAME,
+            &plugin_name(),
         ))
         .map_err(|_| ConnectionError::IsScriptLoadedCall)?
         .body()
@@ -102,27 +133,52 @@ impl KWinScripting for Connection {
     }
 }
 
-fn load_kwin_script() -> Result<(), ConnectionError> {
-    let dbus = block_on(Connection::session()).map_err(|_| ConnectionError::ClientSession)?;
-    if !dbus.is_script_loaded()? {
-        let init_script = || {
-            let temp_file_path = KwinScriptTempFile::new();
-            std::fs::write(&temp_file_path.0, KWIN_SCRIPT).map_err(|_| ConnectionError::WriteScriptToTempFile)?;
-            let script_obj_id = dbus.load_script(&temp_file_path.0)?;
-            dbus.start_script(script_obj_id)?;
-            Ok(())
+// Unload the kwin-scripts left by instances killed before they could unload them
+fn unload_stale_scripts(dbus: &Connection) {
+    let Ok(entries) = std::fs::read_dir(temp_dir()) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(script_file_pid) else {
+            continue;
         };
-        if let Err(err) = init_script() {
-            debug!("Trying to unload kwin-script plugin ('{KWIN_SCRIPT_PLUGIN_NAME}').");
-            match dbus.unload_script() {
-                Err(err) => debug!("Error unloading plugin ('{err:?}'). It may still be loaded and could cause future runs of xremap to fail."),
-                Ok(unloaded) if unloaded => debug!("Successfully unloaded plugin."),
-                Ok(_) => debug!("Plugin was not loaded in the first place."),
-            }
-            return Err(err);
+        // The pid may have been reused by another process since
+        let alive = Process::new(pid as i32)
+            .and_then(|process| process.stat())
+            .is_ok_and(|stat| stat.comm.starts_with("xremap"));
+        if alive {
+            continue;
         }
+        match dbus.unload_script(&plugin_name_of(pid)) {
+            Ok(_) => debug!("Unloaded stale kwin-script plugin ('{}').", plugin_name_of(pid)),
+            Err(err) => debug!("Error unloading stale plugin ('{err:?}')."),
+        }
+        let _ = std::fs::remove_file(entry.path());
+    }
+}
+
+fn load_kwin_script() -> Result<Option<KwinScriptTempFile>, ConnectionError> {
+    let dbus = block_on(Connection::session()).map_err(|_| ConnectionError::ClientSession)?;
+    unload_stale_scripts(&dbus);
+    if dbus.is_script_loaded()? {
+        return Ok(None);
     }
-    Ok(())
+    let init_script = || {
+        let temp_file_path = KwinScriptTempFile::new();
+        std::fs::write(&temp_file_path.0, kwin_script(&bus_name()))
+            .map_err(|_| ConnectionError::WriteScriptToTempFile)?;
+        let script_obj_id = dbus.load_script(&temp_file_path.0)?;
+        dbus.start_script(script_obj_id)?;
+        Ok(temp_file_path)
+    };
+    init_script().map(Some).inspect_err(|_| {
+        debug!("Trying to unload kwin-script plugin ('{}').", plugin_name());
+        match dbus.unload_script(&plugin_name()) {
+            Err(err) => debug!("Error unloading plugin ('{err:?}'). It may still be loaded and could cause future runs of xremap to fail."),
+            Ok(unloaded) if unloaded => debug!("Successfully unloaded plugin."),
+            Ok(_) => debug!("Plugin was not loaded in the first place."),
+        }
+    })
 }
 
 impl KdeClient {
@@ -131,12 +187,19 @@ impl KdeClient {
             title: String::new(),
             res_name: String::new(),
             res_class: String::new(),
+            pid: None,
+            properties: None,
         }));
-        KdeClient { active_window }
+        KdeClient {
+            active_window,
+            script_file: None,
+        }
     }
 
     fn connect(&mut self) -> Result<(), ConnectionError> {
-        load_kwin_script()?;
+        if let Some(script_file) = load_kwin_script()? {
+            self.script_file = Some(script_file);
+        }
 
         let active_window = Arc::clone(&self.active_window);
         let (tx, rx) = channel();
@@ -146,7 +209,7 @@ impl KdeClient {
                 let awi = ActiveWindowInterface { active_window };
 
                 let connection = Builder::session()?
-                    .name("com.k0kubun.Xremap")?
+                    .name(bus_name())?
                     .serve_at("/com/k0kubun/Xremap", awi)?
                     .build();
 
@@ -169,6 +232,22 @@ impl KdeClient {
     }
 }
 
+impl Drop for KdeClient {
+    fn drop(&mut self) {
+        if self.script_file.is_none() {
+            return;
+        }
+        // Otherwise the script stays loaded until KWin restarts
+        match block_on(Connection::session()).map_err(|_| ConnectionError::ClientSession) {
+            Ok(dbus) => match dbus.unload_script(&plugin_name()) {
+                Ok(_) => debug!("Unloaded kwin-script plugin ('{}').", plugin_name()),
+                Err(err) => debug!("Error unloading plugin ('{err:?}')."),
+            },
+            Err(err) => debug!("Error unloading plugin ('{err:?}')."),
+        }
+    }
+}
+
 impl Client for KdeClient {
     fn supported(&mut self) -> bool {
         let conn_res = self.connect();
@@ -188,8 +267,18 @@ impl Client for KdeClient {
     }
 
     fn current_pid(&mut self) -> Option<u32> {
-        // TODO not implemented
-        None
+        let aw = self.active_window.lock().ok()?;
+        aw.pid
+    }
+
+    fn current_resource_name(&mut self) -> Option<String> {
+        let aw = self.active_window.lock().ok()?;
+        Some(aw.res_name.clone())
+    }
+
+    fn current_window_properties(&mut self) -> Option<WindowProperties> {
+        let aw = self.active_window.lock().ok()?;
+        aw.properties.clone()
     }
 }
 
@@ -217,6 +306,9 @@ struct ActiveWindow {
     res_class: String,
     res_name: String,
     title: String,
+    pid: Option<u32>,
+    // Until the kwin-script reports them
+    properties: Option<WindowProperties>,
 }
 
 struct ActiveWindowInterface {
@@ -225,12 +317,60 @@ struct ActiveWindowInterface {
 
 #[interface(name = "com.k0kubun.Xremap")]
 impl ActiveWindowInterface {
+    // Called by kwin-scripts loaded by older versions of xremap, which stay loaded until KWin restarts
     fn notify_active_window(&mut self, caption: String, res_class: String, res_name: String) {
+        self.update(caption, res_class, res_name, None);
+    }
+
+    fn notify_active_window_with_pid(&mut self, caption: String, res_class: String, res_name: String, pid: String) {
+        // KWin reports 0 for windows without a known process
+        let pid = pid.parse::<u32>().ok().filter(|&pid| pid > 0);
+        self.update(caption, res_class, res_name, pid);
+    }
+
+    // Called after notify_active_window_with_pid, and whenever the properties of the active window change
+    fn notify_active_window_properties(&mut self, desktop: String, output: String, fullscreen: bool) {
+        debug!("active window: desktop: '{desktop}', output: '{output}', fullscreen: {fullscreen}");
+        let mut aw = self.active_window.lock().unwrap();
+        aw.properties = Some(WindowProperties {
+            workspace: Some(desktop),
+            output: Some(output),
+            fullscreen,
+            // KWin stacks windows rather than tiling them, so the floating state is not reported
+            floating: false,
+            ..Default::default()
+        });
+    }
+}
+
+impl ActiveWindowInterface {
+    fn update(&mut self, caption: String, res_class: String, res_name: String, pid: Option<u32>) {
         // I want to always print this, since it is the only way to know what the resource class of applications is.
-        println!("active window: caption: '{caption}', class: '{res_class}', name: '{res_name}'");
+        println!("active window: caption: '{caption}', class: '{res_class}', name: '{res_name}', pid: {pid:?}");
         let mut aw = self.active_window.lock().unwrap();
         aw.title = caption;
         aw.res_class = res_class;
         aw.res_name = res_name;
+        aw.pid = pid;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kwin_script_bus_name() {
+        let script = kwin_script("com.k0kubun.Xremap.Instance1234");
+        assert!(script.contains(r#"var xremapBusName = "com.k0kubun.Xremap.Instance1234";"#));
+        // The interface name stays the same
+        assert!(script.contains(r#""com.k0kubun.Xremap","#));
+    }
+
+    #[test]
+    fn test_script_file_pid() {
+        assert_eq!(script_file_pid("xremap-1234-kwin-script.js"), Some(1234));
+        assert_eq!(script_file_pid("xremap-kwin-script.js"), None);
+        assert_eq!(script_file_pid("other-1234-kwin-script.js"), None);
     }
 }
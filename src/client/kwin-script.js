@@ -1,15 +1,56 @@
+// Replaced by the D-Bus name of the xremap instance loading this script
+var xremapBusName = "com.k0kubun.Xremap";
+
 function notifyActiveWindow(client) {
     callDBus(
-        "com.k0kubun.Xremap",
+        xremapBusName,
         "/com/k0kubun/Xremap",
         "com.k0kubun.Xremap",
-        "NotifyActiveWindow",
+        "NotifyActiveWindowWithPid",
         "caption" in client ? client.caption : "",
         "resourceClass" in client ? client.resourceClass : "",
-        "resourceName" in client ? client.resourceName : ""
+        "resourceName" in client ? client.resourceName : "",
+        // As a string, since callDBus may convert a number to either an integer or a double
+        "pid" in client ? String(client.pid) : ""
+    );
+    watchActiveWindow(client);
+}
+
+// The window whose properties are reported until another one is activated
+var activeWindow = null;
+
+function notifyActiveWindowProperties() {
+    if (!activeWindow) {
+        return;
+    }
+    callDBus(
+        xremapBusName,
+        "/com/k0kubun/Xremap",
+        "com.k0kubun.Xremap",
+        "NotifyActiveWindowProperties",
+        // The active window is always on the current virtual desktop
+        workspace.windowList ? workspace.currentDesktop.name : workspace.desktopName(workspace.currentDesktop),
+        // kde 5 only knows the screen number
+        activeWindow.output ? activeWindow.output.name : String(activeWindow.screen),
+        Boolean(activeWindow.fullScreen)
     );
 }
 
+function watchActiveWindow(client) {
+    if (activeWindow) {
+        activeWindow.fullScreenChanged.disconnect(notifyActiveWindowProperties);
+        (activeWindow.outputChanged || activeWindow.screenChanged).disconnect(notifyActiveWindowProperties);
+    }
+    activeWindow = client;
+    if (activeWindow) {
+        activeWindow.fullScreenChanged.connect(notifyActiveWindowProperties);
+        (activeWindow.outputChanged || activeWindow.screenChanged).connect(notifyActiveWindowProperties);
+    }
+    notifyActiveWindowProperties();
+}
+
+workspace.currentDesktopChanged.connect(notifyActiveWindowProperties);
+
 if (workspace.windowList) {
     // kde 6
     workspace.windowActivated.connect(notifyActiveWindow);
@@ -1,8 +1,53 @@
+use anyhow::bail;
+use clap::ValueEnum;
+use std::env;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+
 pub trait Client {
     fn supported(&mut self) -> bool;
     fn current_application(&mut self) -> Option<String>;
     fn current_window(&mut self) -> Option<String>;
     fn current_pid(&mut self) -> Option<u32>;
+    // Instance part of the window class, for clients reporting it apart from current_application
+    fn current_resource_name(&mut self) -> Option<String> {
+        None
+    }
+    // Workspace, output and state of the focused window, for clients of compositors that report them
+    fn current_window_properties(&mut self) -> Option<WindowProperties> {
+        None
+    }
+
+    // A file descriptor that becomes readable when the focused window may have changed.
+    // Clients returning one are only queried again after on_focus_fd_readable returns true.
+    fn focus_fd(&mut self) -> Option<RawFd> {
+        None
+    }
+    // Consume what made focus_fd readable, and return whether the focused window or its properties changed
+    fn on_focus_fd_readable(&mut self) -> bool {
+        true
+    }
+}
+
+// Where the focused window is and how it is shown
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WindowProperties {
+    pub workspace: Option<String>,
+    pub output: Option<String>,
+    pub fullscreen: bool,
+    pub floating: bool,
+    // Marks set on the window, e.g. with Sway's mark command
+    pub marks: Vec<String>,
+}
+
+// What is known about the focused window, cached between focus changes
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WindowSnapshot {
+    pub application: Option<String>,
+    pub title: Option<String>,
+    pub pid: Option<u32>,
+    pub resource_name: Option<String>,
+    pub properties: Option<WindowProperties>,
 }
 
 pub struct WMClient {
@@ -11,6 +56,9 @@ pub struct WMClient {
     supported: Option<bool>,
     last_application: String,
     last_window: String,
+    // focus_fd of the client the snapshot was taken for
+    focus_fd: Option<RawFd>,
+    snapshot: Option<WindowSnapshot>,
 }
 
 impl WMClient {
@@ -21,19 +69,70 @@ impl WMClient {
             supported: None,
             last_application: String::new(),
             last_window: String::new(),
+            focus_fd: None,
+            snapshot: None,
         }
     }
-    pub fn current_window(&mut self) -> Option<String> {
+
+    fn supported(&mut self) -> bool {
         if self.supported.is_none() {
             let supported = self.client.supported();
             self.supported = Some(supported);
             println!("application-client: {} (supported: {})", self.name, supported);
         }
-        if !self.supported.unwrap() {
+        self.supported.unwrap()
+    }
+
+    // Select(2) on this to call on_focus_fd_readable. It may change, e.g. when the client reconnects.
+    pub fn focus_fd(&mut self) -> Option<RawFd> {
+        if !self.supported() {
+            return None;
+        }
+        let focus_fd = self.client.focus_fd();
+        if focus_fd != self.focus_fd {
+            self.focus_fd = focus_fd;
+            self.snapshot = None;
+            if focus_fd.is_some() {
+                self.take_snapshot();
+            }
+        }
+        focus_fd
+    }
+
+    // Return whether the focused window or its properties changed
+    pub fn on_focus_fd_readable(&mut self) -> bool {
+        if !self.client.on_focus_fd_readable() {
+            return false;
+        }
+        if self.focus_fd.is_none() {
+            return true;
+        }
+        let snapshot = self.snapshot.take();
+        self.take_snapshot();
+        snapshot != self.snapshot
+    }
+
+    fn take_snapshot(&mut self) {
+        let snapshot = WindowSnapshot {
+            application: self.client.current_application(),
+            title: self.client.current_window(),
+            pid: self.client.current_pid(),
+            resource_name: self.client.current_resource_name(),
+            properties: self.client.current_window_properties(),
+        };
+        log::debug!("Focused window: {snapshot:?}");
+        self.snapshot = Some(snapshot);
+    }
+
+    pub fn current_window(&mut self) -> Option<String> {
+        if !self.supported() {
             return None;
         }
 
-        let result = self.client.current_window();
+        let result = match &self.snapshot {
+            Some(snapshot) => snapshot.title.clone(),
+            None => self.client.current_window(),
+        };
         if let Some(window) = &result {
             if &self.last_window != window {
                 self.last_window = window.clone();
@@ -44,16 +143,14 @@ impl WMClient {
     }
 
     pub fn current_application(&mut self) -> Option<String> {
-        if self.supported.is_none() {
-            let supported = self.client.supported();
-            self.supported = Some(supported);
-            println!("application-client: {} (supported: {})", self.name, supported);
-        }
-        if !self.supported.unwrap() {
+        if !self.supported() {
             return None;
         }
 
-        let result = self.client.current_application();
+        let result = match &self.snapshot {
+            Some(snapshot) => snapshot.application.clone(),
+            None => self.client.current_application(),
+        };
         if let Some(application) = &result {
             if &self.last_application != application {
                 self.last_application = application.clone();
@@ -64,69 +161,315 @@ impl WMClient {
     }
 
     pub fn current_pid(&mut self) -> Option<u32> {
-        if self.supported.is_none() {
-            let supported = self.client.supported();
-            self.supported = Some(supported);
-            println!("application-client: {} (supported: {})", self.name, supported);
+        if !self.supported() {
+            return None;
+        }
+
+        match &self.snapshot {
+            Some(snapshot) => snapshot.pid,
+            None => self.client.current_pid(),
         }
-        if !self.supported.unwrap() {
+    }
+
+    pub fn current_resource_name(&mut self) -> Option<String> {
+        if !self.supported() {
             return None;
         }
 
-        self.client.current_pid()
+        match &self.snapshot {
+            Some(snapshot) => snapshot.resource_name.clone(),
+            None => self.client.current_resource_name(),
+        }
+    }
+
+    pub fn current_window_properties(&mut self) -> Option<WindowProperties> {
+        if !self.supported() {
+            return None;
+        }
+
+        match &self.snapshot {
+            Some(snapshot) => snapshot.properties.clone(),
+            None => self.client.current_window_properties(),
+        }
     }
 }
 
+mod console_client;
+mod external_client;
 #[cfg(feature = "gnome")]
 mod gnome_client;
-#[cfg(feature = "gnome")]
-pub fn build_client() -> WMClient {
-    WMClient::new("GNOME", Box::new(gnome_client::GnomeClient::new()))
-}
-
+#[cfg(feature = "hypr")]
+mod hypr_client;
 #[cfg(feature = "kde")]
 mod kde_client;
-#[cfg(feature = "kde")]
-pub fn build_client() -> WMClient {
-    WMClient::new("KDE", Box::new(kde_client::KdeClient::new()))
+mod null_client;
+#[cfg(feature = "sway")]
+mod sway_client;
+#[cfg(feature = "wlroots")]
+mod wlroots_client;
+#[cfg(feature = "x11")]
+mod x11_client;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientKind {
+    /// GNOME Shell with the xremap extension
+    Gnome,
+    /// KDE Plasma through a KWin script
+    Kde,
+    /// Hyprland
+    Hypr,
+    /// Sway or i3
+    Sway,
+    /// Wayland compositors with wlr-foreign-toplevel-management or ext-foreign-toplevel-list
+    Wlroots,
+    /// X11 window managers
+    X11,
+    /// Linux virtual consoles, e.g. tty2, without X11 or Wayland
+    Console,
+    /// Focus information written to --focus-input by another program
+    External,
+    /// No application-specific remapping
+    None,
 }
 
-#[cfg(feature = "hypr")]
-mod hypr_client;
-#[cfg(feature = "hypr")]
-pub fn build_client() -> WMClient {
-    WMClient::new("Hypr", Box::new(hypr_client::HyprlandClient::new()))
+impl ClientKind {
+    // Clients behind cargo features, in the order they are preferred when nothing else decides
+    const ALL: [ClientKind; 6] = [
+        ClientKind::Gnome,
+        ClientKind::Kde,
+        ClientKind::Hypr,
+        ClientKind::Sway,
+        ClientKind::Wlroots,
+        ClientKind::X11,
+    ];
+
+    fn is_built(self) -> bool {
+        match self {
+            ClientKind::Gnome => cfg!(feature = "gnome"),
+            ClientKind::Kde => cfg!(feature = "kde"),
+            ClientKind::Hypr => cfg!(feature = "hypr"),
+            ClientKind::Sway => cfg!(feature = "sway"),
+            ClientKind::Wlroots => cfg!(feature = "wlroots"),
+            ClientKind::X11 => cfg!(feature = "x11"),
+            ClientKind::Console | ClientKind::External | ClientKind::None => true,
+        }
+    }
 }
 
-#[cfg(feature = "x11")]
-mod x11_client;
-#[cfg(feature = "x11")]
-pub fn build_client() -> WMClient {
-    WMClient::new("X11", Box::new(x11_client::X11Client::new()))
+// Pick a client from --client, or from the environment of the desktop session
+pub fn build_client(kind: Option<ClientKind>, focus_input: Option<PathBuf>) -> anyhow::Result<WMClient> {
+    let kind = match (kind, &focus_input) {
+        (Some(kind), Some(_)) if kind != ClientKind::External => {
+            bail!("--focus-input needs --client external, not the {kind:?} client")
+        }
+        (Some(kind), _) => {
+            println!("application-client: {kind:?} (chosen by --client)");
+            kind
+        }
+        (None, Some(_)) => {
+            println!("application-client: External (chosen by --focus-input)");
+            ClientKind::External
+        }
+        (None, None) => {
+            let (kind, reason) = detect_client(|name| env::var(name).ok(), console_client::is_text_mode);
+            println!("application-client: {kind:?} (chosen since {reason}, override it with --client)");
+            kind
+        }
+    };
+
+    Ok(match kind {
+        #[cfg(feature = "gnome")]
+        ClientKind::Gnome => WMClient::new("GNOME", Box::new(gnome_client::GnomeClient::new())),
+        #[cfg(feature = "kde")]
+        ClientKind::Kde => WMClient::new("KDE", Box::new(kde_client::KdeClient::new())),
+        #[cfg(feature = "hypr")]
+        ClientKind::Hypr => WMClient::new("Hypr", Box::new(hypr_client::HyprlandClient::new())),
+        #[cfg(feature = "sway")]
+        ClientKind::Sway => WMClient::new("Sway", Box::new(sway_client::SwayClient::new())),
+        #[cfg(feature = "wlroots")]
+        ClientKind::Wlroots => WMClient::new("wlroots", Box::new(wlroots_client::WlRootsClient::new())),
+        #[cfg(feature = "x11")]
+        ClientKind::X11 => WMClient::new("X11", Box::new(x11_client::X11Client::new())),
+        ClientKind::Console => WMClient::new("console", Box::new(console_client::ConsoleClient::new())),
+        ClientKind::External => match focus_input {
+            Some(path) => WMClient::new("external", Box::new(external_client::ExternalClient::new(path))),
+            None => bail!("--client external needs --focus-input"),
+        },
+        ClientKind::None => WMClient::new("none", Box::new(null_client::NullClient)),
+        #[allow(unreachable_patterns)]
+        kind => bail!("xremap was built without the {kind:?} client. Build it with --features {}", feature(kind)),
+    })
 }
 
-#[cfg(feature = "wlroots")]
-mod wlroots_client;
-#[cfg(feature = "wlroots")]
-pub fn build_client() -> WMClient {
-    WMClient::new("wlroots", Box::new(wlroots_client::WlRootsClient::new()))
+fn feature(kind: ClientKind) -> &'static str {
+    match kind {
+        ClientKind::Gnome => "gnome",
+        ClientKind::Kde => "kde",
+        ClientKind::Hypr => "hypr",
+        ClientKind::Sway => "sway",
+        ClientKind::Wlroots => "wlroots",
+        ClientKind::X11 => "x11",
+        ClientKind::Console | ClientKind::External | ClientKind::None => "",
+    }
 }
 
-#[cfg(not(any(
-    feature = "gnome",
-    feature = "x11",
-    feature = "hypr",
-    feature = "kde",
-    feature = "wlroots"
-)))]
-mod null_client;
-#[cfg(not(any(
-    feature = "gnome",
-    feature = "x11",
-    feature = "hypr",
-    feature = "kde",
-    feature = "wlroots"
-)))]
-pub fn build_client() -> WMClient {
-    WMClient::new("none", Box::new(null_client::NullClient))
+// The first client suggested by the environment that xremap was built with, and why it was chosen
+fn detect_client(var: impl Fn(&str) -> Option<String>, text_mode: impl Fn() -> bool) -> (ClientKind, String) {
+    if let Some(kind) = client_candidates(&var).into_iter().find(|(kind, _)| kind.is_built()) {
+        return kind;
+    }
+    // e.g. run by a system service without the variables of the session
+    let built: Vec<_> = ClientKind::ALL.into_iter().filter(|kind| kind.is_built()).collect();
+    match built[..] {
+        [kind] => (kind, "it is the only client xremap was built with".to_string()),
+        // A graphical session may still be running, only its variables are unknown
+        _ if text_mode() => (ClientKind::Console, "the active virtual console is in text mode".to_string()),
+        _ => (
+            ClientKind::None,
+            "no session variables were found and the active virtual console is not in text mode".to_string(),
+        ),
+    }
+}
+
+// Clients suggested by the environment variables of the session, most specific first
+fn client_candidates(var: impl Fn(&str) -> Option<String>) -> Vec<(ClientKind, String)> {
+    let mut candidates = vec![];
+    if var("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        candidates.push((ClientKind::Hypr, "$HYPRLAND_INSTANCE_SIGNATURE is set".to_string()));
+    }
+    for name in ["SWAYSOCK", "I3SOCK"] {
+        if var(name).is_some() {
+            candidates.push((ClientKind::Sway, format!("${name} is set")));
+            break;
+        }
+    }
+    if var("WAYLAND_DISPLAY").is_some() {
+        // e.g. GNOME, KDE, or ubuntu:GNOME
+        let desktop = var("XDG_CURRENT_DESKTOP").unwrap_or_default();
+        for desktop in desktop.split(':') {
+            let reason = format!("$XDG_CURRENT_DESKTOP is {desktop} on Wayland");
+            match desktop.to_lowercase().as_str() {
+                "gnome" => candidates.push((ClientKind::Gnome, reason)),
+                "kde" => candidates.push((ClientKind::Kde, reason)),
+                _ => {}
+            }
+        }
+        candidates.push((ClientKind::Wlroots, "$WAYLAND_DISPLAY is set".to_string()));
+    }
+    // Also for X11 applications through Xwayland when the Wayland compositor isn't supported
+    if var("DISPLAY").is_some() {
+        candidates.push((ClientKind::X11, "$DISPLAY is set".to_string()));
+    }
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    // Counts queries, and pretends to notify focus changes
+    struct FocusClient {
+        application: Rc<Cell<&'static str>>,
+        queries: Rc<Cell<usize>>,
+    }
+
+    impl Client for FocusClient {
+        fn supported(&mut self) -> bool {
+            true
+        }
+        fn current_window(&mut self) -> Option<String> {
+            None
+        }
+        fn current_application(&mut self) -> Option<String> {
+            self.queries.set(self.queries.get() + 1);
+            Some(self.application.get().to_string())
+        }
+        fn current_pid(&mut self) -> Option<u32> {
+            None
+        }
+        fn focus_fd(&mut self) -> Option<RawFd> {
+            Some(0)
+        }
+    }
+
+    #[test]
+    fn test_snapshot_until_focus_change() {
+        let application = Rc::new(Cell::new("firefox"));
+        let queries = Rc::new(Cell::new(0));
+        let mut client = WMClient::new(
+            "focus",
+            Box::new(FocusClient {
+                application: application.clone(),
+                queries: queries.clone(),
+            }),
+        );
+        assert_eq!(client.focus_fd(), Some(0));
+        assert_eq!(queries.get(), 1);
+
+        application.set("kitty");
+        assert_eq!(client.current_application(), Some("firefox".to_string()));
+        assert_eq!(client.current_application(), Some("firefox".to_string()));
+        assert_eq!(queries.get(), 1);
+
+        client.on_focus_fd_readable();
+        assert_eq!(client.current_application(), Some("kitty".to_string()));
+        assert_eq!(queries.get(), 2);
+    }
+
+    #[test]
+    fn test_client_candidates() {
+        let candidates = |vars: &[(&str, &str)]| -> Vec<ClientKind> {
+            let vars: HashMap<_, _> = vars.iter().cloned().collect();
+            client_candidates(|name| vars.get(name).map(|value| value.to_string()))
+                .into_iter()
+                .map(|(kind, _)| kind)
+                .collect()
+        };
+        assert_eq!(candidates(&[]), vec![]);
+        assert_eq!(candidates(&[("DISPLAY", ":0")]), vec![ClientKind::X11]);
+        assert_eq!(
+            candidates(&[
+                ("XDG_CURRENT_DESKTOP", "ubuntu:GNOME"),
+                ("WAYLAND_DISPLAY", "wayland-0"),
+                ("DISPLAY", ":0")
+            ]),
+            vec![ClientKind::Gnome, ClientKind::Wlroots, ClientKind::X11]
+        );
+        // GNOME on X11
+        assert_eq!(candidates(&[("XDG_CURRENT_DESKTOP", "GNOME"), ("DISPLAY", ":0")]), vec![ClientKind::X11]);
+        assert_eq!(
+            candidates(&[("XDG_CURRENT_DESKTOP", "KDE"), ("WAYLAND_DISPLAY", "wayland-0")]),
+            vec![ClientKind::Kde, ClientKind::Wlroots]
+        );
+        assert_eq!(
+            candidates(&[
+                ("SWAYSOCK", "/run/user/1000/sway-ipc.sock"),
+                ("WAYLAND_DISPLAY", "wayland-1")
+            ]),
+            vec![ClientKind::Sway, ClientKind::Wlroots]
+        );
+        assert_eq!(
+            candidates(&[("I3SOCK", "/run/user/1000/i3/ipc-socket.1234"), ("DISPLAY", ":0")]),
+            vec![ClientKind::Sway, ClientKind::X11]
+        );
+        assert_eq!(
+            candidates(&[("HYPRLAND_INSTANCE_SIGNATURE", "abc"), ("WAYLAND_DISPLAY", "wayland-1")]),
+            vec![ClientKind::Hypr, ClientKind::Wlroots]
+        );
+    }
+
+    #[test]
+    fn test_detect_client_fallback() {
+        let built: Vec<_> = ClientKind::ALL.into_iter().filter(|kind| kind.is_built()).collect();
+        let (text_mode, graphics_mode) = match built[..] {
+            // The only client built is chosen regardless of the console
+            [kind] => (kind, kind),
+            _ => (ClientKind::Console, ClientKind::None),
+        };
+        assert_eq!(detect_client(|_| None, || true).0, text_mode);
+        assert_eq!(detect_client(|_| None, || false).0, graphics_mode);
+    }
 }
@@ -0,0 +1,526 @@
+use crate::client::{self, Client};
+use serde::Deserialize;
+use std::env;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+// i3-ipc messages: "i3-ipc", the payload length and the message type in native byte order, and a JSON payload
+const MAGIC: &[u8; 6] = b"i3-ipc";
+const HEADER_LEN: usize = MAGIC.len() + 8;
+const SUBSCRIBE: u32 = 2;
+const GET_TREE: u32 = 4;
+// Events have the highest bit of the type set
+const EVENT_WORKSPACE: u32 = 0x80000000;
+const EVENT_WINDOW: u32 = 0x80000003;
+
+// Talks i3-ipc to Sway or i3. One socket receives window and workspace events, another one queries the tree.
+pub struct SwayClient {
+    socket_path: Option<PathBuf>,
+    events: Option<UnixStream>,
+    // Incomplete messages read from the event socket
+    buffer: Vec<u8>,
+    // The focused container, updated by events
+    active_window: Option<Node>,
+    // Workspace and output of the focused container
+    location: Location,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Location {
+    workspace: Option<String>,
+    output: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Node {
+    id: i64,
+    #[serde(rename = "type")]
+    node_type: String,
+    name: Option<String>,
+    // Wayland windows on Sway
+    app_id: Option<String>,
+    // X11 windows on i3, and on Sway through Xwayland
+    window_properties: Option<WindowProperties>,
+    pid: Option<i32>,
+    marks: Vec<String>,
+    // Output of a workspace
+    output: Option<String>,
+    // 0 when not fullscreen
+    fullscreen_mode: u8,
+    // Floating state of a window on i3, e.g. user_on. Floating windows are floating_con nodes on Sway.
+    floating: Option<String>,
+    focused: bool,
+    nodes: Vec<Node>,
+    floating_nodes: Vec<Node>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct WindowProperties {
+    class: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WindowEvent {
+    change: String,
+    container: Node,
+}
+
+#[derive(Deserialize)]
+struct WorkspaceEvent {
+    change: String,
+    current: Option<Node>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.floating_nodes.is_empty()
+    }
+
+    fn is_floating(&self) -> bool {
+        self.node_type == "floating_con" || self.floating.as_ref().is_some_and(|floating| floating.ends_with("_on"))
+    }
+
+    // The focused node under this one, with the workspace and output it is on
+    fn into_focused(self, mut location: Location) -> Option<(Node, Location)> {
+        match self.node_type.as_str() {
+            "output" => location.output = self.name.clone(),
+            "workspace" => location.workspace = self.name.clone(),
+            _ => {}
+        }
+        if self.focused {
+            return Some((self, location));
+        }
+        self.nodes
+            .into_iter()
+            .chain(self.floating_nodes)
+            .find_map(|node| node.into_focused(location.clone()))
+    }
+}
+
+impl SwayClient {
+    pub fn new() -> SwayClient {
+        let socket_path = env::var_os("SWAYSOCK")
+            .or_else(|| env::var_os("I3SOCK"))
+            .map(PathBuf::from);
+        SwayClient::with_socket_path(socket_path)
+    }
+
+    fn with_socket_path(socket_path: Option<PathBuf>) -> SwayClient {
+        SwayClient {
+            socket_path,
+            events: None,
+            buffer: vec![],
+            active_window: None,
+            location: Location::default(),
+        }
+    }
+
+    fn connect(&mut self) -> anyhow::Result<()> {
+        let Some(socket_path) = &self.socket_path else {
+            anyhow::bail!("$SWAYSOCK is not set");
+        };
+        let mut events = UnixStream::connect(socket_path)?;
+        self.buffer.clear();
+        send_message(&mut events, SUBSCRIBE, br#"["window","workspace"]"#)?;
+        // Events are only sent after the reply to SUBSCRIBE
+        let (_, reply) = recv_message(&mut events)?;
+        if !serde_json::from_slice::<serde_json::Value>(&reply)?["success"]
+            .as_bool()
+            .unwrap_or(false)
+        {
+            anyhow::bail!("Failed to subscribe to window events");
+        }
+        events.set_nonblocking(true)?;
+        self.events = Some(events);
+        self.buffer.clear();
+        // Events only report changes, so start from the currently focused container
+        self.refresh();
+        Ok(())
+    }
+
+    // Find the focused container, its workspace and output in the tree
+    fn refresh(&mut self) {
+        let focused = self.get_tree().and_then(|tree| tree.into_focused(Location::default()));
+        match focused {
+            // A workspace itself is focused when it has no windows
+            Some((node, location)) => {
+                self.location = location;
+                self.active_window = if node.node_type == "workspace" {
+                    None
+                } else {
+                    Some(node)
+                };
+            }
+            None => {
+                self.location = Location::default();
+                self.active_window = None;
+            }
+        }
+        log::debug!("Sway focused window: {:?} on {:?}", self.active_window, self.location);
+    }
+
+    fn get_tree(&self) -> Option<Node> {
+        let mut stream = UnixStream::connect(self.socket_path.as_ref()?).ok()?;
+        send_message(&mut stream, GET_TREE, b"").ok()?;
+        let (_, payload) = recv_message(&mut stream).ok()?;
+        serde_json::from_slice(&payload).ok()
+    }
+
+    // Read the event socket until it would block, returning complete messages
+    fn read_events(&mut self) -> Vec<(u32, Vec<u8>)> {
+        let Some(events) = &mut self.events else {
+            return vec![];
+        };
+        let mut buf = [0; 4096];
+        loop {
+            match events.read(&mut buf) {
+                Ok(0) => {
+                    println!("Sway closed its IPC socket");
+                    self.disconnect();
+                    break;
+                }
+                Ok(n) => self.buffer.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("Failed to read Sway events: {e}");
+                    self.disconnect();
+                    break;
+                }
+            }
+        }
+
+        let mut messages = vec![];
+        loop {
+            match parse_header(&self.buffer) {
+                Ok(Some((message_type, len))) if self.buffer.len() >= HEADER_LEN + len => {
+                    let payload = self.buffer[HEADER_LEN..HEADER_LEN + len].to_vec();
+                    self.buffer.drain(..HEADER_LEN + len);
+                    messages.push((message_type, payload));
+                }
+                Ok(_) => break,
+                // The rest of the stream can't be split into messages anymore
+                Err(e) => {
+                    println!("Failed to read Sway events: {e}");
+                    self.disconnect();
+                    break;
+                }
+            }
+        }
+        messages
+    }
+
+    fn disconnect(&mut self) {
+        self.events = None;
+        self.buffer.clear();
+        self.active_window = None;
+        self.location = Location::default();
+    }
+
+    fn is_active(&self, node: &Node) -> bool {
+        self.active_window.as_ref().is_some_and(|window| window.id == node.id)
+    }
+
+    // Apply an event, returning whether the focused container or its properties changed
+    fn on_event(&mut self, message_type: u32, payload: &[u8]) -> bool {
+        match message_type {
+            EVENT_WINDOW => {
+                let Ok(event) = serde_json::from_slice::<WindowEvent>(payload) else {
+                    return false;
+                };
+                match event.change.as_str() {
+                    "focus" => {
+                        self.active_window = Some(event.container);
+                        true
+                    }
+                    "title" | "mark" | "fullscreen_mode" | "floating" if self.is_active(&event.container) => {
+                        self.active_window = Some(event.container);
+                        true
+                    }
+                    // The workspace of the focused container is not part of the event
+                    "move" if self.is_active(&event.container) => {
+                        self.refresh();
+                        true
+                    }
+                    "close" if self.is_active(&event.container) => {
+                        self.active_window = None;
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            EVENT_WORKSPACE => {
+                let Ok(WorkspaceEvent {
+                    change,
+                    current: Some(current),
+                }) = serde_json::from_slice(payload)
+                else {
+                    return false;
+                };
+                match change.as_str() {
+                    "focus" => {}
+                    // The workspace may be the one of the focused container
+                    "rename" | "move" => {
+                        self.refresh();
+                        return true;
+                    }
+                    _ => return false,
+                }
+                self.location = Location {
+                    workspace: current.name.clone(),
+                    output: current.output.clone(),
+                };
+                // A window event follows unless the workspace has no windows
+                if current.is_empty() {
+                    self.active_window = None;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Client for SwayClient {
+    fn supported(&mut self) -> bool {
+        if let Err(e) = self.connect() {
+            println!("Failed to connect to Sway: {e}");
+            return false;
+        }
+        true
+    }
+
+    fn current_window(&mut self) -> Option<String> {
+        self.active_window.as_ref()?.name.clone()
+    }
+
+    fn current_application(&mut self) -> Option<String> {
+        let window = self.active_window.as_ref()?;
+        window
+            .app_id
+            .clone()
+            .or_else(|| window.window_properties.as_ref()?.class.clone())
+    }
+
+    fn current_pid(&mut self) -> Option<u32> {
+        u32::try_from(self.active_window.as_ref()?.pid?)
+            .ok()
+            .filter(|&pid| pid > 0)
+    }
+
+    // An empty workspace is focused without a window, but still has a location
+    fn current_window_properties(&mut self) -> Option<client::WindowProperties> {
+        let window = self.active_window.as_ref();
+        if window.is_none() && self.location.workspace.is_none() && self.location.output.is_none() {
+            return None;
+        }
+        Some(client::WindowProperties {
+            workspace: self.location.workspace.clone(),
+            output: self.location.output.clone(),
+            fullscreen: window.is_some_and(|window| window.fullscreen_mode != 0),
+            floating: window.is_some_and(Node::is_floating),
+            marks: window.map(|window| window.marks.clone()).unwrap_or_default(),
+        })
+    }
+
+    // Reconnects once the event socket was closed, e.g. when Sway restarted
+    fn focus_fd(&mut self) -> Option<RawFd> {
+        if self.events.is_none() {
+            match self.connect() {
+                Ok(()) => println!("Reconnected to Sway"),
+                Err(e) => log::debug!("Failed to reconnect to Sway: {e}"),
+            }
+        }
+        self.events.as_ref().map(AsRawFd::as_raw_fd)
+    }
+
+    fn on_focus_fd_readable(&mut self) -> bool {
+        let disconnected = self.events.is_none();
+        let mut changed = false;
+        for (message_type, payload) in self.read_events() {
+            changed |= self.on_event(message_type, &payload);
+        }
+        changed || (self.events.is_none() && !disconnected)
+    }
+}
+
+fn send_message(stream: &mut UnixStream, message_type: u32, payload: &[u8]) -> io::Result<()> {
+    let mut message = MAGIC.to_vec();
+    message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    message.extend_from_slice(&message_type.to_ne_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)
+}
+
+fn recv_message(stream: &mut UnixStream) -> io::Result<(u32, Vec<u8>)> {
+    let mut header = [0; HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    let Some((message_type, len)) = parse_header(&header)? else {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "incomplete i3-ipc header"));
+    };
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok((message_type, payload))
+}
+
+// Message type and payload length, once the buffer holds a complete header
+fn parse_header(buffer: &[u8]) -> io::Result<Option<(u32, usize)>> {
+    let magic_len = buffer.len().min(MAGIC.len());
+    if buffer[..magic_len] != MAGIC[..magic_len] {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not an i3-ipc message"));
+    }
+    if buffer.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    let len = u32::from_ne_bytes(buffer[6..10].try_into().unwrap());
+    let message_type = u32::from_ne_bytes(buffer[10..14].try_into().unwrap());
+    Ok(Some((message_type, len as usize)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // Answers SUBSCRIBE and GET_TREE like Sway, handing subscribed streams over to the test
+    fn serve(listener: UnixListener, tree: Arc<Mutex<String>>, subscribed: Sender<UnixStream>) {
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let (message_type, _) = recv_message(&mut stream).unwrap();
+                match message_type {
+                    SUBSCRIBE => {
+                        send_message(&mut stream, SUBSCRIBE, br#"{"success":true}"#).unwrap();
+                        subscribed.send(stream).unwrap();
+                    }
+                    GET_TREE => send_message(&mut stream, GET_TREE, tree.lock().unwrap().as_bytes()).unwrap(),
+                    _ => panic!("unexpected message type {message_type}"),
+                }
+            }
+        });
+    }
+
+    fn window_json(id: i64, app_id: &str, title: &str, pid: i32, focused: bool) -> String {
+        format!(
+            r#"{{"id":{id},"type":"con","name":"{title}","app_id":"{app_id}","pid":{pid},"marks":[],"focused":{focused},"nodes":[],"floating_nodes":[]}}"#
+        )
+    }
+
+    fn tree_json(workspace: &str, windows: &[String]) -> String {
+        format!(
+            r#"{{"id":1,"type":"root","nodes":[{{"id":2,"type":"output","name":"eDP-1","nodes":[{{"id":3,"type":"workspace","name":"{workspace}","output":"eDP-1","nodes":[{}]}}]}}]}}"#,
+            windows.join(",")
+        )
+    }
+
+    #[test]
+    fn test_window_events() {
+        let socket_path = env::temp_dir().join(format!("xremap-sway-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+        let tree = Arc::new(Mutex::new(tree_json(
+            "1",
+            &[
+                window_json(10, "kitty", "~", 100, true),
+                window_json(11, "firefox", "Mozilla Firefox", 200, false),
+            ],
+        )));
+        let (tx, rx) = channel();
+        serve(UnixListener::bind(&socket_path).unwrap(), tree.clone(), tx);
+
+        let mut client = SwayClient::with_socket_path(Some(socket_path.clone()));
+        assert!(client.supported());
+        let mut events = rx.recv().unwrap();
+        assert!(client.focus_fd().is_some());
+        assert_eq!(client.current_application(), Some("kitty".to_string()));
+        assert_eq!(client.current_pid(), Some(100));
+        let properties = client.current_window_properties().unwrap();
+        assert_eq!(properties.workspace, Some("1".to_string()));
+        assert_eq!(properties.output, Some("eDP-1".to_string()));
+        assert!(!properties.fullscreen);
+
+        // Focus moves to another window, and the event arrives in two reads
+        let mut message = vec![];
+        let event =
+            format!(r#"{{"change":"focus","container":{}}}"#, window_json(11, "firefox", "Mozilla Firefox", 200, true));
+        message.extend_from_slice(MAGIC);
+        message.extend_from_slice(&(event.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&EVENT_WINDOW.to_ne_bytes());
+        message.extend_from_slice(event.as_bytes());
+        events.write_all(&message[..20]).unwrap();
+        assert!(!client.on_focus_fd_readable());
+        assert_eq!(client.current_application(), Some("kitty".to_string()));
+        events.write_all(&message[20..]).unwrap();
+        assert!(client.on_focus_fd_readable());
+        assert_eq!(client.current_application(), Some("firefox".to_string()));
+        assert_eq!(client.current_window(), Some("Mozilla Firefox".to_string()));
+        assert_eq!(client.current_pid(), Some(200));
+
+        // Only title changes of the focused window matter
+        let event = format!(r#"{{"change":"title","container":{}}}"#, window_json(10, "kitty", "vim", 100, false));
+        send_message(&mut events, EVENT_WINDOW, event.as_bytes()).unwrap();
+        assert!(!client.on_focus_fd_readable());
+        let event = format!(r#"{{"change":"title","container":{}}}"#, window_json(11, "firefox", "GitHub", 200, true));
+        send_message(&mut events, EVENT_WINDOW, event.as_bytes()).unwrap();
+        assert!(client.on_focus_fd_readable());
+        assert_eq!(client.current_window(), Some("GitHub".to_string()));
+
+        // The focused window goes fullscreen
+        let window =
+            window_json(11, "firefox", "GitHub", 200, true).replace("\"marks\"", "\"fullscreen_mode\":1,\"marks\"");
+        let event = format!(r#"{{"change":"fullscreen_mode","container":{window}}}"#);
+        send_message(&mut events, EVENT_WINDOW, event.as_bytes()).unwrap();
+        assert!(client.on_focus_fd_readable());
+        assert!(client.current_window_properties().unwrap().fullscreen);
+
+        // The focused window is marked
+        let event =
+            format!(r#"{{"change":"mark","container":{}}}"#, window.replace(r#""marks":[]"#, r#""marks":["scratch"]"#));
+        send_message(&mut events, EVENT_WINDOW, event.as_bytes()).unwrap();
+        assert!(client.on_focus_fd_readable());
+        assert_eq!(client.current_window_properties().unwrap().marks, vec!["scratch".to_string()]);
+
+        // An X11 window through Xwayland
+        let event = r#"{"change":"focus","container":{"id":12,"type":"floating_con","name":"xterm","app_id":null,"window_properties":{"class":"XTerm"},"pid":300}}"#;
+        send_message(&mut events, EVENT_WINDOW, event.as_bytes()).unwrap();
+        assert!(client.on_focus_fd_readable());
+        assert_eq!(client.current_application(), Some("XTerm".to_string()));
+        assert!(client.current_window_properties().unwrap().floating);
+
+        // Focus moves to an empty workspace
+        let event = r#"{"change":"focus","current":{"id":4,"type":"workspace","name":"2","output":"HDMI-A-1","nodes":[],"floating_nodes":[]}}"#;
+        send_message(&mut events, EVENT_WORKSPACE, event.as_bytes()).unwrap();
+        assert!(client.on_focus_fd_readable());
+        assert_eq!(client.current_application(), None);
+        assert_eq!(
+            client.current_window_properties(),
+            Some(client::WindowProperties {
+                workspace: Some("2".to_string()),
+                output: Some("HDMI-A-1".to_string()),
+                fullscreen: false,
+                floating: false,
+                marks: vec![],
+            })
+        );
+
+        // A message that isn't i3-ipc drops the connection, which is reconnected to
+        events.write_all(b"garbage that never parses").unwrap();
+        assert!(client.on_focus_fd_readable());
+        assert_eq!(client.current_application(), None);
+        assert!(client.focus_fd().is_some());
+        let events = rx.recv().unwrap();
+        assert_eq!(client.current_application(), Some("kitty".to_string()));
+
+        // Sway exits
+        drop(events);
+        assert!(client.on_focus_fd_readable());
+
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use anyhow::{Context, Result};
 use wayland_client::{
@@ -9,24 +10,33 @@ use wayland_client::{
     Connection, Dispatch, EventQueue, Proxy, QueueHandle,
 };
 
+use wayland_protocols::ext::foreign_toplevel_list::v1::client::{
+    ext_foreign_toplevel_handle_v1::{Event as ExtHandleEvent, ExtForeignToplevelHandleV1},
+    ext_foreign_toplevel_list_v1::{Event as ExtListEvent, ExtForeignToplevelListV1},
+};
 use wayland_protocols_wlr::foreign_toplevel::v1::client::{
     zwlr_foreign_toplevel_handle_v1::{Event as HandleEvent, State as HandleState, ZwlrForeignToplevelHandleV1},
     zwlr_foreign_toplevel_manager_v1::{Event as ManagerEvent, ZwlrForeignToplevelManagerV1},
 };
 
-use crate::client::Client;
+use crate::client::{Client, WindowProperties};
 
 #[derive(Default, Debug)]
 struct State {
     active_window: Option<ObjectId>,
     windows: HashMap<ObjectId, String>,
     titles: HashMap<ObjectId, String>,
+    // Only reported by the wlr protocol
+    fullscreen: HashSet<ObjectId>,
 }
 
 #[derive(Default)]
 pub struct WlRootsClient {
+    connection: Option<Connection>,
     queue: Option<EventQueue<State>>,
     state: State,
+    // Whether the wlr protocol is bound, rather than ext_foreign_toplevel_list_v1
+    wlr: bool,
 }
 
 impl WlRootsClient {
@@ -38,16 +48,50 @@ impl WlRootsClient {
         let connection = Connection::connect_to_env()?;
         let (globals, mut queue) = registry_queue_init::<State>(&connection)?;
 
-        globals
+        // Prefer the wlr protocol, which reports the activated toplevel
+        self.wlr = globals
             .bind::<ZwlrForeignToplevelManagerV1, _, _>(&queue.handle(), 1..=3, ())
-            .context("wlr_foreign_toplevel_management_unstable_v1 protocol is not supported")?;
+            .is_ok();
+        if !self.wlr {
+            globals
+                .bind::<ExtForeignToplevelListV1, _, _>(&queue.handle(), 1..=1, ())
+                .context("Neither wlr_foreign_toplevel_management_unstable_v1 nor ext_foreign_toplevel_list_v1 protocol is supported")?;
+            println!("warning: ext_foreign_toplevel_list_v1 doesn't report the focused window. Application-specific remaps won't apply.");
+        }
 
         queue.roundtrip(&mut self.state)?;
 
+        self.connection = Some(connection);
         self.queue = Some(queue);
 
         Ok(())
     }
+
+    // Toplevels of the previous connection are forgotten, and unknown until the next roundtrip
+    fn reconnect(&mut self) -> bool {
+        self.connection = None;
+        self.queue = None;
+        self.state = State::default();
+        match self.connect() {
+            Ok(()) => {
+                log::debug!("Reconnected to wayland");
+                true
+            }
+            Err(err) => {
+                log::error!("{err}");
+                false
+            }
+        }
+    }
+
+    // Reconnects when the connection was lost
+    fn roundtrip(&mut self) -> bool {
+        let connected = self
+            .queue
+            .as_mut()
+            .is_some_and(|queue| queue.roundtrip(&mut self.state).is_ok());
+        connected || self.reconnect()
+    }
 }
 
 impl Client for WlRootsClient {
@@ -60,17 +104,44 @@ impl Client for WlRootsClient {
             }
         }
     }
-    fn current_window(&mut self) -> Option<String> {
-        let queue = self.queue.as_mut()?;
+    fn current_pid(&mut self) -> Option<u32> {
+        None
+    }
 
-        if let Err(_) = queue.roundtrip(&mut self.state) {
-            // try to reconnect
-            if let Err(err) = self.connect() {
-                log::error!("{err}");
-                return None;
-            }
+    fn focus_fd(&mut self) -> Option<RawFd> {
+        Some(self.connection.as_ref()?.backend().poll_fd().as_raw_fd())
+    }
+
+    fn on_focus_fd_readable(&mut self) -> bool {
+        let Some(queue) = self.queue.as_mut() else {
+            return false;
+        };
+        let active_window = self.state.active_window.clone();
+        let window = active_window
+            .as_ref()
+            .and_then(|id| self.state.windows.get(id))
+            .cloned();
+        let title = active_window.as_ref().and_then(|id| self.state.titles.get(id)).cloned();
+        let fullscreen = active_window
+            .as_ref()
+            .is_some_and(|id| self.state.fullscreen.contains(id));
+
+        if let Err(err) = queue.blocking_dispatch(&mut self.state) {
+            log::error!("{err}");
+            self.reconnect();
+            return true;
+        }
 
-            log::debug!("Reconnected to wayland");
+        let id = self.state.active_window.as_ref();
+        id != active_window.as_ref()
+            || id.and_then(|id| self.state.windows.get(id)) != window.as_ref()
+            || id.and_then(|id| self.state.titles.get(id)) != title.as_ref()
+            || id.is_some_and(|id| self.state.fullscreen.contains(id)) != fullscreen
+    }
+
+    fn current_window(&mut self) -> Option<String> {
+        if !self.roundtrip() {
+            return None;
         }
 
         let id = self.state.active_window.as_ref()?;
@@ -78,21 +149,25 @@ impl Client for WlRootsClient {
     }
 
     fn current_application(&mut self) -> Option<String> {
-        let queue = self.queue.as_mut()?;
-
-        if let Err(_) = queue.roundtrip(&mut self.state) {
-            // try to reconnect
-            if let Err(err) = self.connect() {
-                log::error!("{err}");
-                return None;
-            }
-
-            log::debug!("Reconnected to wayland");
+        if !self.roundtrip() {
+            return None;
         }
 
         let id = self.state.active_window.as_ref()?;
         self.state.windows.get(id).cloned()
     }
+
+    // Toplevels are not told their workspace or output names, so only the fullscreen state is known
+    fn current_window_properties(&mut self) -> Option<WindowProperties> {
+        if !self.wlr {
+            return None;
+        }
+        let id = self.state.active_window.as_ref()?;
+        Some(WindowProperties {
+            fullscreen: self.state.fullscreen.contains(id),
+            ..Default::default()
+        })
+    }
 }
 
 impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
@@ -147,12 +222,65 @@ impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for State {
             HandleEvent::Closed => {
                 state.windows.remove(&handle.id());
                 state.titles.remove(&handle.id());
+                state.fullscreen.remove(&handle.id());
             }
             HandleEvent::State { state: handle_state } => {
                 let activated = HandleState::Activated as u8;
                 if handle_state.contains(&activated) {
                     state.active_window = Some(handle.id());
                 }
+                let fullscreen = HandleState::Fullscreen as u8;
+                if handle_state.contains(&fullscreen) {
+                    state.fullscreen.insert(handle.id());
+                } else {
+                    state.fullscreen.remove(&handle.id());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtForeignToplevelListV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &ExtForeignToplevelListV1,
+        event: ExtListEvent,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<State>,
+    ) {
+        if let ExtListEvent::Toplevel { toplevel } = event {
+            state.windows.insert(toplevel.id(), "<unknown>".into());
+            state.titles.insert(toplevel.id(), "<unknown>".into());
+        }
+    }
+
+    event_created_child!(State, ExtForeignToplevelListV1, [
+        _ => (ExtForeignToplevelHandleV1, ())
+    ]);
+}
+
+impl Dispatch<ExtForeignToplevelHandleV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        handle: &ExtForeignToplevelHandleV1,
+        event: ExtHandleEvent,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            ExtHandleEvent::AppId { app_id } => {
+                state.windows.insert(handle.id(), app_id);
+            }
+            ExtHandleEvent::Title { title } => {
+                state.titles.insert(handle.id(), title);
+            }
+            ExtHandleEvent::Closed => {
+                state.windows.remove(&handle.id());
+                state.titles.remove(&handle.id());
+                handle.destroy();
             }
             _ => {}
         }
@@ -1,20 +1,111 @@
 use crate::client::Client;
 use anyhow::bail;
 use std::env;
+use std::os::unix::io::{AsRawFd, RawFd};
+use x11rb::connection::Connection;
 use x11rb::cookie::Cookie;
 use x11rb::protocol::xproto::{self};
-use x11rb::protocol::xproto::{AtomEnum, ConnectionExt, Window};
+use x11rb::protocol::xproto::{Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, Window};
+use x11rb::protocol::Event;
 use x11rb::rust_connection::ConnectionError;
 use x11rb::x11_utils::TryParse;
 use x11rb::{protocol::xproto::get_property, rust_connection::RustConnection};
 
 pub struct X11Client {
     connection: Option<RustConnection>,
+    // Interned on the current connection
+    atoms: Option<Atoms>,
+    watcher: Option<FocusWatcher>,
+}
+
+#[derive(Clone, Copy)]
+struct Atoms {
+    net_wm_pid: Atom,
+    net_wm_name: Atom,
+    utf8_string: Atom,
+}
+
+// Receives PropertyNotify events on a connection of its own, so that replies to the queries of
+// X11Client never leave events buffered without its socket being readable.
+struct FocusWatcher {
+    connection: RustConnection,
+    root: Window,
+    net_active_window: Atom,
+    // Properties of the focused window that xremap reads: WM_CLASS, WM_NAME, _NET_WM_NAME and _NET_WM_PID
+    window_properties: [Atom; 4],
+    // The focused window and its ancestors, whose property changes may change WM_CLASS or the title
+    windows: Vec<Window>,
+}
+
+impl FocusWatcher {
+    fn new() -> anyhow::Result<FocusWatcher> {
+        let (connection, screen) = x11rb::connect(None)?;
+        let root = connection.setup().roots[screen].root;
+        let net_active_window = connection.intern_atom(false, b"_NET_ACTIVE_WINDOW")?.reply()?.atom;
+        let net_wm_name = connection.intern_atom(false, b"_NET_WM_NAME")?.reply()?.atom;
+        let net_wm_pid = connection.intern_atom(false, b"_NET_WM_PID")?.reply()?.atom;
+        let window_properties = [
+            AtomEnum::WM_CLASS.into(),
+            AtomEnum::WM_NAME.into(),
+            net_wm_name,
+            net_wm_pid,
+        ];
+        // Without a window manager maintaining _NET_ACTIVE_WINDOW, focus changes can't be noticed
+        let reply = get_property(&connection, false, root, net_active_window, AtomEnum::WINDOW, 0, 1)?.reply()?;
+        if reply.value.is_empty() {
+            bail!("_NET_ACTIVE_WINDOW is not supported by the window manager");
+        }
+        let attributes = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+        connection.change_window_attributes(root, &attributes)?;
+        connection.flush()?;
+        Ok(FocusWatcher {
+            connection,
+            root,
+            net_active_window,
+            window_properties,
+            windows: vec![],
+        })
+    }
+
+    // Watch the properties of other windows than before
+    fn watch(&mut self, windows: Vec<Window>) -> Result<(), ConnectionError> {
+        for &window in self.windows.iter().filter(|window| !windows.contains(window)) {
+            let attributes = ChangeWindowAttributesAux::new().event_mask(EventMask::NO_EVENT);
+            // Windows may be gone already. Errors are received as events and ignored.
+            self.connection.change_window_attributes(window, &attributes)?;
+        }
+        for &window in windows.iter().filter(|window| !self.windows.contains(window)) {
+            let attributes = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+            self.connection.change_window_attributes(window, &attributes)?;
+        }
+        self.windows = windows;
+        self.connection.flush()
+    }
+
+    // Drain events, returning whether the focus or a property of the focused window changed
+    fn read_events(&mut self) -> Result<bool, ConnectionError> {
+        let mut changed = false;
+        while let Some(event) = self.connection.poll_for_event()? {
+            // Other properties, e.g. _NET_WM_USER_TIME, change on every key press
+            if let Event::PropertyNotify(event) = event {
+                if event.window == self.root {
+                    changed |= event.atom == self.net_active_window;
+                } else if self.windows.contains(&event.window) {
+                    changed |= self.window_properties.contains(&event.atom);
+                }
+            }
+        }
+        Ok(changed)
+    }
 }
 
 impl X11Client {
     pub fn new() -> X11Client {
-        X11Client { connection: None }
+        X11Client {
+            connection: None,
+            atoms: None,
+            watcher: None,
+        }
     }
 
     fn connect(&mut self) {
@@ -30,6 +121,7 @@ impl X11Client {
     }
 
     fn reconnect(&mut self) {
+        self.atoms = None;
         match x11rb::connect(None) {
             Ok((connection, _)) => self.connection = Some(connection),
             Err(error) => {
@@ -40,17 +132,82 @@ impl X11Client {
             }
         }
     }
+
+    // The focused window and its ancestors below the root window
+    fn focused_windows(&mut self) -> Vec<Window> {
+        let mut windows = vec![];
+        let mut window = get_focus_window(self);
+        // 0 is None and 1 is PointerRoot
+        while let Some(current) = window.filter(|&window| window > 1) {
+            windows.push(current);
+            window = get_parent_window(self, current);
+        }
+        // The last one is the root window
+        windows.pop();
+        windows
+    }
+
+    fn watch_focused_windows(&mut self) {
+        let windows = self.focused_windows();
+        if let Some(watcher) = &mut self.watcher {
+            if let Err(error) = watcher.watch(windows) {
+                println!("warning: Stopped watching X11 focus changes: {error}");
+                self.watcher = None;
+            }
+        }
+    }
 }
 
 impl Client for X11Client {
     fn supported(&mut self) -> bool {
         self.connect();
+        if self.connection.is_some() {
+            match FocusWatcher::new() {
+                Ok(watcher) => {
+                    self.watcher = Some(watcher);
+                    self.watch_focused_windows();
+                }
+                Err(error) => log::debug!("Querying the focused window on each key event: {error}"),
+            }
+        }
         return self.connection.is_some();
         // TODO: Test XGetInputFocus and focused_window > 0?
     }
+
+    fn focus_fd(&mut self) -> Option<RawFd> {
+        self.watcher
+            .as_ref()
+            .map(|watcher| watcher.connection.stream().as_raw_fd())
+    }
+
+    fn on_focus_fd_readable(&mut self) -> bool {
+        let Some(watcher) = &mut self.watcher else {
+            return false;
+        };
+        match watcher.read_events() {
+            Ok(true) => {
+                self.watch_focused_windows();
+                true
+            }
+            Ok(false) => false,
+            Err(error) => {
+                println!("warning: Stopped watching X11 focus changes: {error}");
+                self.watcher = None;
+                true
+            }
+        }
+    }
     fn current_window(&mut self) -> Option<String> {
-        // TODO:  not implemented
-        None
+        self.connect();
+        let mut window = get_focus_window(self)?;
+        loop {
+            // The focused window may be a child of the one with the title, e.g. a Focus-Proxy-Window
+            if let Some(title) = get_title(self, window) {
+                return Some(title);
+            }
+
+            window = get_parent_window(self, window)?;
+        }
     }
 
     fn current_application(&mut self) -> Option<String> {
@@ -87,16 +244,69 @@ fn get_parent_window(client: &mut X11Client, window: Window) -> Option<Window> {
         .ok()
 }
 
+fn get_atoms(client: &mut X11Client) -> Option<Atoms> {
+    if client.atoms.is_none() {
+        let mut intern = |name: &'static [u8]| {
+            get_cookie_reply_with_reconnect(client, |conn| conn.intern_atom(false, name))
+                .map(|reply| reply.atom)
+                .ok()
+        };
+        let atoms = Atoms {
+            net_wm_pid: intern(b"_NET_WM_PID")?,
+            net_wm_name: intern(b"_NET_WM_NAME")?,
+            utf8_string: intern(b"UTF8_STRING")?,
+        };
+        client.atoms = Some(atoms);
+    }
+    client.atoms
+}
+
 fn get_net_wm_pid(client: &mut X11Client, window: Window) -> Option<u32> {
+    let net_wm_pid = get_atoms(client)?.net_wm_pid;
     let reply = get_cookie_reply_with_reconnect(client, |conn| {
-        let net_wm_pid_atom = conn.intern_atom(false, b"_NET_WM_PID").unwrap().reply().unwrap().atom;
-        get_property(conn, false, window, net_wm_pid_atom, AtomEnum::CARDINAL, 0, 1024)
+        get_property(conn, false, window, net_wm_pid, AtomEnum::CARDINAL, 0, 1024)
     })
     .ok()?;
 
     return reply.value32().and_then(|mut x| x.next());
 }
 
+// _NET_WM_NAME, or WM_NAME for clients not supporting EWMH.
+// A property that can't be read is treated as unset, so that the caller moves on to the parent window.
+fn get_title(client: &mut X11Client, window: Window) -> Option<String> {
+    if let Some(atoms) = get_atoms(client) {
+        let reply = get_cookie_reply_with_reconnect(client, |conn| {
+            get_property(conn, false, window, atoms.net_wm_name, atoms.utf8_string, 0, 1024)
+        });
+        if let Some(title) = reply.ok().and_then(|reply| parse_text_property(&reply.value, true)) {
+            return Some(title);
+        }
+    }
+
+    let reply = get_cookie_reply_with_reconnect(client, |conn| {
+        get_property(conn, false, window, AtomEnum::WM_NAME, AtomEnum::ANY, 0, 1024)
+    })
+    .ok()?;
+    // WM_NAME is usually a Latin-1 STRING, but can be set with any encoding
+    parse_text_property(&reply.value, reply.type_ != u32::from(AtomEnum::STRING))
+}
+
+// Decode a text property, which may be terminated by NUL. Non-UTF-8 encodings like COMPOUND_TEXT
+// are decoded lossily, which is correct for their ASCII subset.
+fn parse_text_property(value: &[u8], utf8: bool) -> Option<String> {
+    let end = value.iter().position(|byte| *byte == 0).unwrap_or(value.len());
+    let value = &value[..end];
+    if value.is_empty() {
+        return None;
+    }
+    if utf8 {
+        Some(String::from_utf8_lossy(value).into_owned())
+    } else {
+        // Latin-1 is the first 256 code points of Unicode
+        Some(value.iter().map(|&byte| byte as char).collect())
+    }
+}
+
 fn get_wm_class(client: &mut X11Client, window: Window) -> Option<String> {
     let reply = get_cookie_reply_with_reconnect(client, |conn| {
         get_property(conn, false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 1024)
@@ -145,3 +355,37 @@ fn get_cookie_reply<T: TryParse>(
         None => bail!("No connection to X11"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_utf8_title() {
+        let title = parse_text_property("xremap – Visual Studio Code".as_bytes(), true);
+        assert_eq!(title, Some("xremap – Visual Studio Code".to_string()));
+    }
+
+    #[test]
+    fn test_parse_latin1_title() {
+        let title = parse_text_property(b"caf\xe9 - xterm", false);
+        assert_eq!(title, Some("café - xterm".to_string()));
+    }
+
+    #[test]
+    fn test_parse_title_terminated_by_nul() {
+        assert_eq!(parse_text_property(b"vim\0", true), Some("vim".to_string()));
+        assert_eq!(parse_text_property(b"vim\0", false), Some("vim".to_string()));
+    }
+
+    #[test]
+    fn test_parse_empty_title() {
+        assert_eq!(parse_text_property(b"", true), None);
+        assert_eq!(parse_text_property(b"\0", false), None);
+    }
+
+    #[test]
+    fn test_parse_invalid_utf8_title() {
+        assert_eq!(parse_text_property(b"a\xffb", true), Some("a\u{fffd}b".to_string()));
+    }
+}
@@ -0,0 +1,57 @@
+use crate::config::application::OnlyOrNot;
+use crate::config::keymap_action::{Actions, KeymapAction};
+use serde::{Deserialize, Deserializer};
+
+use super::scope::Scope;
+use super::window_matchers::WindowMatchers;
+
+// Actions run when the focus moves into or out of the windows matching the conditions
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FocusHook {
+    #[serde(default = "String::new")]
+    pub name: String,
+    pub application: Option<OnlyOrNot>,
+    pub window: Option<OnlyOrNot>,
+    pub resource_name: Option<OnlyOrNot>,
+    pub workspace: Option<OnlyOrNot>,
+    pub output: Option<OnlyOrNot>,
+    pub fullscreen: Option<bool>,
+    pub floating: Option<bool>,
+    pub marks: Option<OnlyOrNot>,
+    pub process: Option<OnlyOrNot>,
+    pub foreground_process: Option<OnlyOrNot>,
+    pub cgroup: Option<OnlyOrNot>,
+    #[serde(default)]
+    pub scope: Scope,
+    #[serde(default, deserialize_with = "deserialize_actions")]
+    pub on_focus: Vec<KeymapAction>,
+    #[serde(default, deserialize_with = "deserialize_actions")]
+    pub on_blur: Vec<KeymapAction>,
+}
+
+impl FocusHook {
+    pub fn window_matchers(&self) -> WindowMatchers<'_> {
+        WindowMatchers {
+            application: self.application.as_ref(),
+            window: self.window.as_ref(),
+            resource_name: self.resource_name.as_ref(),
+            workspace: self.workspace.as_ref(),
+            output: self.output.as_ref(),
+            fullscreen: self.fullscreen,
+            floating: self.floating,
+            marks: self.marks.as_ref(),
+            process: self.process.as_ref(),
+            foreground_process: self.foreground_process.as_ref(),
+            cgroup: self.cgroup.as_ref(),
+            scope: self.scope,
+        }
+    }
+}
+
+fn deserialize_actions<'de, D>(deserializer: D) -> Result<Vec<KeymapAction>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Actions::deserialize(deserializer)?.into_vec())
+}
@@ -8,6 +8,8 @@ use std::collections::HashMap;
 
 use super::device::Device;
 use super::key_press::Modifier;
+use super::scope::Scope;
+use super::window_matchers::WindowMatchers;
 
 // Config interface
 #[derive(Debug, Deserialize)]
@@ -20,10 +22,21 @@ pub struct Keymap {
     pub remap: HashMap<KeyPress, Vec<KeymapAction>>,
     pub application: Option<OnlyOrNot>,
     pub window: Option<OnlyOrNot>,
+    pub resource_name: Option<OnlyOrNot>,
+    pub workspace: Option<OnlyOrNot>,
+    pub output: Option<OnlyOrNot>,
+    pub fullscreen: Option<bool>,
+    pub floating: Option<bool>,
+    pub marks: Option<OnlyOrNot>,
+    pub process: Option<OnlyOrNot>,
+    pub foreground_process: Option<OnlyOrNot>,
+    pub cgroup: Option<OnlyOrNot>,
     pub device: Option<Device>,
     #[serde(default, deserialize_with = "deserialize_string_or_vec")]
     pub mode: Option<Vec<String>>,
     #[serde(default)]
+    pub scope: Scope,
+    #[serde(default)]
     pub exact_match: bool,
 }
 
@@ -45,11 +58,40 @@ pub struct KeymapEntry {
     pub modifiers: Vec<Modifier>,
     pub application: Option<OnlyOrNot>,
     pub title: Option<OnlyOrNot>,
+    pub resource_name: Option<OnlyOrNot>,
+    pub workspace: Option<OnlyOrNot>,
+    pub output: Option<OnlyOrNot>,
+    pub fullscreen: Option<bool>,
+    pub floating: Option<bool>,
+    pub marks: Option<OnlyOrNot>,
+    pub process: Option<OnlyOrNot>,
+    pub foreground_process: Option<OnlyOrNot>,
+    pub cgroup: Option<OnlyOrNot>,
     pub device: Option<Device>,
     pub mode: Option<Vec<String>>,
+    pub scope: Scope,
     pub exact_match: bool,
 }
 
+impl KeymapEntry {
+    pub fn window_matchers(&self) -> WindowMatchers<'_> {
+        WindowMatchers {
+            application: self.application.as_ref(),
+            window: self.title.as_ref(),
+            resource_name: self.resource_name.as_ref(),
+            workspace: self.workspace.as_ref(),
+            output: self.output.as_ref(),
+            fullscreen: self.fullscreen,
+            floating: self.floating,
+            marks: self.marks.as_ref(),
+            process: self.process.as_ref(),
+            foreground_process: self.foreground_process.as_ref(),
+            cgroup: self.cgroup.as_ref(),
+            scope: self.scope,
+        }
+    }
+}
+
 // Convert an array of keymaps to a single hashmap whose key is a triggering key.
 //
 // For each key, Vec<KeymapEntry> is scanned once, matching the exact modifiers,
@@ -69,8 +111,18 @@ pub fn build_keymap_table(keymaps: &Vec<Keymap>) -> HashMap<Key, Vec<KeymapEntry
                 modifiers: key_press.modifiers.clone(),
                 application: keymap.application.clone(),
                 title: keymap.window.clone(),
+                resource_name: keymap.resource_name.clone(),
+                workspace: keymap.workspace.clone(),
+                output: keymap.output.clone(),
+                fullscreen: keymap.fullscreen,
+                floating: keymap.floating,
+                marks: keymap.marks.clone(),
+                process: keymap.process.clone(),
+                foreground_process: keymap.foreground_process.clone(),
+                cgroup: keymap.cgroup.clone(),
                 device: keymap.device.clone(),
                 mode: keymap.mode.clone(),
+                scope: keymap.scope,
                 exact_match: keymap.exact_match,
             });
             table.insert(key_press.key, entries);
@@ -1,5 +1,6 @@
 pub mod application;
 pub mod device;
+pub mod focus_hook;
 mod key;
 pub mod key_press;
 pub mod keymap;
@@ -8,13 +9,16 @@ mod modmap;
 pub mod modmap_action;
 
 pub mod remap;
+pub mod scope;
 #[cfg(test)]
 mod tests;
+pub mod window_matchers;
 
 extern crate serde_yaml;
 extern crate toml;
 
 use evdev::KeyCode as Key;
+use focus_hook::FocusHook;
 use keymap::Keymap;
 use modmap::Modmap;
 use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
@@ -34,6 +38,8 @@ pub struct Config {
     pub modmap: Vec<Modmap>,
     #[serde(default = "Vec::new")]
     pub keymap: Vec<Keymap>,
+    #[serde(default = "Vec::new")]
+    pub focus_hooks: Vec<FocusHook>,
     #[serde(default = "default_mode")]
     pub default_mode: String,
     #[serde(deserialize_with = "deserialize_virtual_modifiers", default = "Vec::new")]
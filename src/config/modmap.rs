@@ -7,6 +7,8 @@ use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
 
 use super::device::Device;
+use super::scope::Scope;
+use super::window_matchers::WindowMatchers;
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -18,9 +20,39 @@ pub struct Modmap {
     pub remap: HashMap<Key, ModmapAction>,
     pub application: Option<OnlyOrNot>,
     pub window: Option<OnlyOrNot>,
+    pub resource_name: Option<OnlyOrNot>,
+    pub workspace: Option<OnlyOrNot>,
+    pub output: Option<OnlyOrNot>,
+    pub fullscreen: Option<bool>,
+    pub floating: Option<bool>,
+    pub marks: Option<OnlyOrNot>,
+    pub process: Option<OnlyOrNot>,
+    pub foreground_process: Option<OnlyOrNot>,
+    pub cgroup: Option<OnlyOrNot>,
     pub device: Option<Device>,
     #[serde(default, deserialize_with = "deserialize_string_or_vec")]
     pub mode: Option<Vec<String>>,
+    #[serde(default)]
+    pub scope: Scope,
+}
+
+impl Modmap {
+    pub fn window_matchers(&self) -> WindowMatchers<'_> {
+        WindowMatchers {
+            application: self.application.as_ref(),
+            window: self.window.as_ref(),
+            resource_name: self.resource_name.as_ref(),
+            workspace: self.workspace.as_ref(),
+            output: self.output.as_ref(),
+            fullscreen: self.fullscreen,
+            floating: self.floating,
+            marks: self.marks.as_ref(),
+            process: self.process.as_ref(),
+            foreground_process: self.foreground_process.as_ref(),
+            cgroup: self.cgroup.as_ref(),
+            scope: self.scope,
+        }
+    }
 }
 
 fn deserialize_remap<'de, D>(deserializer: D) -> Result<HashMap<Key, ModmapAction>, D::Error>
@@ -0,0 +1,14 @@
+use serde::Deserialize;
+
+// Which windows a modmap or keymap applies to, relative to the command wrapped by xremap
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    // Windows of the wrapped command and its descendants
+    #[default]
+    Child,
+    // Every other window
+    Outside,
+    // All windows
+    Global,
+}
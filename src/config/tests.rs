@@ -141,6 +141,51 @@ fn test_yaml_keymap_application() {
     "})
 }
 
+#[test]
+fn test_yaml_keymap_resource_name() {
+    yaml_assert_parse(indoc! {"
+    keymap:
+      - remap:
+          Alt-Enter: Ctrl-Enter
+        application:
+          only: org.kde.konsole
+        resource_name:
+          not: /^yakuake/
+    "})
+}
+
+#[test]
+fn test_yaml_keymap_window_properties() {
+    yaml_assert_parse(indoc! {"
+    keymap:
+      - remap:
+          C-w: C-q
+        workspace:
+          only: ['1', /^web/]
+        output:
+          not: HDMI-A-1
+        fullscreen: false
+        floating: true
+        marks:
+          only: scratch
+    "})
+}
+
+#[test]
+fn test_yaml_focus_hooks() {
+    yaml_assert_parse(indoc! {"
+    focus_hooks:
+      - name: Terminal
+        application:
+          only: [Gnome-terminal, kitty]
+        scope: global
+        on_focus: { set_mode: insert }
+        on_blur:
+          - set_mode: default
+          - launch: [notify-send, left the terminal]
+    "})
+}
+
 #[test]
 fn test_yaml_keymap_array() {
     yaml_assert_parse(indoc! {"
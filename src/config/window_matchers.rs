@@ -0,0 +1,19 @@
+use crate::config::application::OnlyOrNot;
+
+use super::scope::Scope;
+
+// Conditions on the focused window, shared by modmap, keymap and focus_hooks
+pub struct WindowMatchers<'a> {
+    pub application: Option<&'a OnlyOrNot>,
+    pub window: Option<&'a OnlyOrNot>,
+    pub resource_name: Option<&'a OnlyOrNot>,
+    pub workspace: Option<&'a OnlyOrNot>,
+    pub output: Option<&'a OnlyOrNot>,
+    pub fullscreen: Option<bool>,
+    pub floating: Option<bool>,
+    pub marks: Option<&'a OnlyOrNot>,
+    pub process: Option<&'a OnlyOrNot>,
+    pub foreground_process: Option<&'a OnlyOrNot>,
+    pub cgroup: Option<&'a OnlyOrNot>,
+    pub scope: Scope,
+}
@@ -86,6 +86,17 @@ pub fn device_watcher(watch: bool) -> anyhow::Result<Option<Inotify>> {
     }
 }
 
+// name: filters for the output devices of the xremap instances running before this one.
+// Those started later are not selected, even when devices are reselected, so that no instances read each other's output.
+pub fn other_instance_filters() -> io::Result<Vec<String>> {
+    let mut devices: Vec<_> = InputDevice::devices()?.filter(InputDevice::is_other_instance).collect();
+    devices.sort();
+    Ok(devices
+        .iter()
+        .map(|device| format!("name:{}", device.device_name()))
+        .collect())
+}
+
 pub fn get_input_devices(
     device_opts: &[String],
     ignore_opts: &[String],
@@ -155,6 +166,10 @@ impl<'a> InputDeviceInfo<'a> {
         if self.path.as_os_str() == filter || self.name == filter {
             return true;
         }
+        // name:xxx only matches the device named xxx, unlike a partial match of its name
+        if let Some(name) = filter.strip_prefix("name:") {
+            return self.name == name;
+        }
         // eventXX shorthand for /dev/input/eventXX
         if filter.starts_with("event") && self.path.file_name().expect("every device path has a file name") == filter {
             return true;
@@ -250,6 +265,9 @@ impl InputDevice {
     pub fn grab(&mut self) -> bool {
         if let Err(error) = self.device.grab() {
             println!("Failed to grab device '{}' at '{}' due to: {error}", self.device_name(), self.path.display());
+            if error.raw_os_error() == Some(nix::libc::EBUSY) {
+                println!("Another xremap instance may have grabbed it. Use --chain to read the output of other instances instead.");
+            }
             false
         } else {
             true
@@ -298,7 +316,8 @@ impl InputDevice {
             return false;
         }
         (if device_filter.is_empty() {
-            self.is_keyboard() || (mouse && self.is_mouse())
+            // Other instances are only read with --chain, since two instances reading each other's output would loop
+            !self.is_other_instance() && (self.is_keyboard() || (mouse && self.is_mouse()))
         } else {
             self.matches_any(device_filter)
         }) && (ignore_filter.is_empty() || !self.matches_any(ignore_filter))
@@ -327,6 +346,12 @@ impl InputDevice {
         unsafe { DEVICE_NAME.as_ref() }.unwrap()
     }
 
+    // The output device of another xremap instance
+    fn is_other_instance(&self) -> bool {
+        let name = self.device_name();
+        (name == "xremap" || name.starts_with("xremap pid=")) && name != Self::current_name()
+    }
+
     fn has_device_name(device_name: &str) -> bool {
         let devices: Vec<_> = match Self::devices() {
             Ok(devices) => devices.collect(),
@@ -13,6 +13,10 @@ pub enum Event<'a> {
     OtherEvents(InputEvent),
     // Timer for nested override reached its timeout
     OverrideTimeout,
+    // The focus fd of the window manager client became readable
+    FocusChange,
+    // The config was replaced with a reloaded one
+    ConfigReload,
 }
 
 #[derive(Debug)]
@@ -1,14 +1,16 @@
 use crate::action::Action;
-use crate::client::WMClient;
+use crate::client::{WMClient, WindowProperties};
 use crate::config::application::OnlyOrNot;
 use crate::config::key_press::{KeyPress, Modifier};
 use crate::config::keymap::{build_override_table, OverrideEntry};
 use crate::config::keymap_action::KeymapAction;
 use crate::config::modmap_action::{Keys, ModmapAction, MultiPurposeKey, PressReleaseKey};
 use crate::config::remap::Remap;
+use crate::config::scope::Scope;
+use crate::config::window_matchers::WindowMatchers;
 use crate::device::InputDeviceInfo;
 use crate::event::{Event, KeyEvent, RelativeEvent};
-use crate::proc_cache::ProcCache;
+use crate::proc_cache::{cgroup_of, foreground_processes, process_attributes, ProcCache, ProcKey};
 use crate::{config, Config};
 use evdev::KeyCode as Key;
 use lazy_static::lazy_static;
@@ -18,6 +20,7 @@ use nix::sys::timerfd::{Expiration, TimerFd, TimerSetTimeFlags};
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::os::unix::io::RawFd;
 use std::time::{Duration, Instant};
 
 // This const is a value used to offset RELATIVE events' scancodes
@@ -37,10 +40,37 @@ pub struct EventHandler {
     extra_modifiers: HashSet<Key>,
     // Make sure the original event is released even if remapping changes while holding the key
     pressed_keys: HashMap<Key, Key>,
+    // Finish each press with the modmap it started with, even if the focus moved to another scope meanwhile
+    pressed_modmaps: HashMap<Key, Option<ModmapAction>>,
+    // Keys released on a scope change while still held, whose repeats and release are ignored
+    flushed_keys: HashSet<Key>,
     // Check the currently active application
     application_client: WMClient,
+    // focus_fd of application_client. Without one, the caches below expire on every key event.
+    focus_fd: Option<RawFd>,
     application_cache: Option<String>,
     title_cache: Option<String>,
+    resource_name_cache: Option<String>,
+    // Workspace, output and fullscreen/floating state of the focused window
+    window_properties_cache: Option<WindowProperties>,
+    // PID of the focused window, translated from the PID namespace of a sandbox
+    pid_cache: Option<Option<i32>>,
+    // The process a PID was translated to, with the PID and application of the window it was reported for.
+    // Translating walks the process trees, so it's done once per focus change, or for clients without
+    // a focus_fd, once the PID or application changes.
+    resolved_pid: Option<(i32, String, ProcKey)>,
+    // exe, comm, cmdline and cwd of the process owning the focused window
+    process_cache: Option<Vec<String>>,
+    // Same as process_cache, for the foreground jobs of the focused terminal
+    foreground_process_cache: Option<Vec<String>>,
+    // Cgroup path of the focused window's process and its components, e.g. its systemd unit
+    cgroup_cache: Option<Vec<String>>,
+    // Whether the focused window belongs to the wrapped command
+    child_cache: Option<bool>,
+    // child_cache of the last key event that computed it, to notice the focus moving to another scope
+    last_child: Option<bool>,
+    // Whether each of config.focus_hooks matched the focused window when they were last checked
+    focused_hooks: Vec<bool>,
     // State machine for multi-purpose keys
     multi_purpose_keys: HashMap<Key, MultiPurposeKeyState>,
     // Current nested remaps
@@ -68,14 +98,33 @@ struct TaggedAction {
 }
 
 impl EventHandler {
-    pub fn new(timer: TimerFd, mode: &str, keypress_delay: Duration, application_client: WMClient) -> EventHandler {
+    pub fn new(
+        timer: TimerFd,
+        mode: &str,
+        keypress_delay: Duration,
+        application_client: WMClient,
+        proc_cache: ProcCache,
+    ) -> EventHandler {
         EventHandler {
             modifiers: HashSet::new(),
             extra_modifiers: HashSet::new(),
             pressed_keys: HashMap::new(),
+            pressed_modmaps: HashMap::new(),
+            flushed_keys: HashSet::new(),
             application_client,
+            focus_fd: None,
             application_cache: None,
             title_cache: None,
+            resource_name_cache: None,
+            window_properties_cache: None,
+            pid_cache: None,
+            resolved_pid: None,
+            process_cache: None,
+            foreground_process_cache: None,
+            cgroup_cache: None,
+            child_cache: None,
+            last_child: None,
+            focused_hooks: vec![],
             multi_purpose_keys: HashMap::new(),
             override_remaps: vec![],
             override_timeout_key: None,
@@ -85,11 +134,22 @@ impl EventHandler {
             escape_next_key: false,
             keypress_delay,
             actions: vec![],
-            proc_cache: ProcCache::new(),
+            proc_cache,
         }
     }
 
-    // Handle an Event and return Actions. This should be the only public method of EventHandler.
+    // File descriptor to select(2) on for Event::FocusChange, if the window manager client can notify focus changes
+    pub fn focus_fd(&mut self) -> Option<RawFd> {
+        let focus_fd = self.application_client.focus_fd();
+        // e.g. the client reconnected, and the focused window was queried again
+        if focus_fd != self.focus_fd {
+            self.focus_fd = focus_fd;
+            self.expire_caches();
+        }
+        focus_fd
+    }
+
+    // Handle an Event and return Actions. This should be the only public method of EventHandler besides focus_fd.
     pub fn on_events(&mut self, events: &Vec<Event>, config: &Config) -> Result<Vec<Action>, Box<dyn Error>> {
         // a vector to collect mouse movement events to be able to send them all at once as one MouseMovementEventCollection.
         let mut mouse_movement_collection: Vec<RelativeEvent> = Vec::new();
@@ -105,6 +165,17 @@ impl EventHandler {
 
                 Event::OtherEvents(event) => self.send_action(Action::InputEvent(*event)),
                 Event::OverrideTimeout => self.timeout_override()?,
+                Event::FocusChange => {
+                    if self.application_client.on_focus_fd_readable() {
+                        self.expire_caches();
+                        self.run_focus_hooks(config)?;
+                    }
+                }
+                Event::ConfigReload => {
+                    // Hooks of the new config start unfocused, whether or not they changed
+                    self.focused_hooks = vec![false; config.focus_hooks.len()];
+                    self.run_focus_hooks(config)?;
+                }
             };
         }
         // if there is at least one mouse movement event, sending all of them as one MouseMovementEventCollection
@@ -121,31 +192,43 @@ impl EventHandler {
         config: &Config,
         device: &InputDeviceInfo,
     ) -> Result<bool, Box<dyn Error>> {
-        self.application_cache = None; // expire cache
-        self.title_cache = None; // expire cache
+        // Otherwise they expire on Event::FocusChange
+        if self.focus_fd.is_none() {
+            self.expire_caches();
+        }
         let key = Key::new(event.code());
         debug!("=> {}: {:?}", event.value(), &key);
-
-        let is_child = if let Some(pid) = self.application_client.current_pid() {
-            debug!("Window PID: {pid}");
-            if self.proc_cache.is_child_or_grandchild(pid as i32) {
-                debug!("Window PID is a child of the current process");
-                true
-            } else {
-                debug!("Window PID is not a child of the current process");
-                false
+        if event.value() == PRESS {
+            self.flushed_keys.remove(&key);
+        } else if self.flushed_keys.contains(&key) {
+            if event.value() == RELEASE {
+                self.flushed_keys.remove(&key);
             }
-        } else {
-            debug!("Could not retrieve window PID");
-            false
-        };
-        if !is_child {
-            self.send_key(&key, event.value());
-            return Ok(true);
+            return Ok(false);
         }
 
         // Apply modmap
-        let mut key_values = if let Some(key_action) = self.find_modmap(config, &key, device) {
+        let key_action = if event.value() == PRESS {
+            // Catch up with focus changes of clients that can't notify them
+            if self.focus_fd.is_none() {
+                self.run_focus_hooks(config)?;
+            }
+            self.flush_on_scope_change(config, device)?;
+            let key_action = self.find_modmap(config, &key, device);
+            self.pressed_modmaps.insert(key, key_action.clone());
+            key_action
+        } else if event.value() == RELEASE {
+            match self.pressed_modmaps.remove(&key) {
+                Some(key_action) => key_action,
+                None => self.find_modmap(config, &key, device),
+            }
+        } else {
+            match self.pressed_modmaps.get(&key) {
+                Some(key_action) => key_action.clone(),
+                None => self.find_modmap(config, &key, device),
+            }
+        };
+        let mut key_values = if let Some(key_action) = key_action {
             self.dispatch_keys(key_action, key, event.value())?
         } else {
             vec![(key, event.value())]
@@ -185,11 +268,131 @@ impl EventHandler {
             self.send_key(&key, value);
         }
 
+        if self.child_cache.is_some() {
+            self.last_child = self.child_cache;
+        }
+
         // Using the Ok() to send a boolean to on_relative_event, which will be used to decide whether to send the original relative event.
         // (True = send the original relative event, false = don't send it.)
         Ok(send_original_relative_event)
     }
 
+    fn expire_caches(&mut self) {
+        self.application_cache = None;
+        self.title_cache = None;
+        self.resource_name_cache = None;
+        self.window_properties_cache = None;
+        self.pid_cache = None;
+        if self.focus_fd.is_some() {
+            self.resolved_pid = None;
+        }
+        self.process_cache = None;
+        self.foreground_process_cache = None;
+        self.cgroup_cache = None;
+        self.child_cache = None;
+    }
+
+    // Run on_blur of focus hooks that stopped matching the focused window, then on_focus of those that started to
+    fn run_focus_hooks(&mut self, config: &Config) -> Result<(), Box<dyn Error>> {
+        if config.focus_hooks.is_empty() {
+            return Ok(());
+        }
+        // Before the first focus change
+        if self.focused_hooks.len() != config.focus_hooks.len() {
+            self.focused_hooks = vec![false; config.focus_hooks.len()];
+        }
+
+        let previous = std::mem::take(&mut self.focused_hooks);
+        for hook in &config.focus_hooks {
+            let focused = self.matches(&hook.window_matchers());
+            self.focused_hooks.push(focused);
+        }
+        for (i, hook) in config.focus_hooks.iter().enumerate() {
+            if previous[i] && !self.focused_hooks[i] {
+                debug!("Focus left {:?}", hook.name);
+                self.dispatch_hook_actions(&hook.on_blur)?;
+            }
+        }
+        for (i, hook) in config.focus_hooks.iter().enumerate() {
+            if !previous[i] && self.focused_hooks[i] {
+                debug!("Focus entered {:?}", hook.name);
+                self.dispatch_hook_actions(&hook.on_focus)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn matches(&mut self, matchers: &WindowMatchers) -> bool {
+        if let Some(window_matcher) = matchers.window {
+            if !self.match_window(window_matcher) {
+                return false;
+            }
+        }
+        if let Some(application_matcher) = matchers.application {
+            if !self.match_application(application_matcher) {
+                return false;
+            }
+        }
+        if let Some(resource_name_matcher) = matchers.resource_name {
+            if !self.match_resource_name(resource_name_matcher) {
+                return false;
+            }
+        }
+        if let Some(workspace_matcher) = matchers.workspace {
+            if !self.match_workspace(workspace_matcher) {
+                return false;
+            }
+        }
+        if let Some(output_matcher) = matchers.output {
+            if !self.match_output(output_matcher) {
+                return false;
+            }
+        }
+        if let Some(fullscreen) = matchers.fullscreen {
+            if self.window_properties().fullscreen != fullscreen {
+                return false;
+            }
+        }
+        if let Some(floating) = matchers.floating {
+            if self.window_properties().floating != floating {
+                return false;
+            }
+        }
+        if let Some(marks_matcher) = matchers.marks {
+            if !self.match_marks(marks_matcher) {
+                return false;
+            }
+        }
+        if let Some(process_matcher) = matchers.process {
+            if !self.match_process(process_matcher) {
+                return false;
+            }
+        }
+        if let Some(process_matcher) = matchers.foreground_process {
+            if !self.match_foreground_process(process_matcher) {
+                return false;
+            }
+        }
+        if let Some(cgroup_matcher) = matchers.cgroup {
+            if !self.match_cgroup(cgroup_matcher) {
+                return false;
+            }
+        }
+        self.match_scope(matchers.scope)
+    }
+
+    fn dispatch_hook_actions(&mut self, actions: &[KeymapAction]) -> Result<(), Box<dyn Error>> {
+        let actions: Vec<TaggedAction> = actions
+            .iter()
+            .map(|action| TaggedAction {
+                action: action.clone(),
+                exact_match: false,
+            })
+            .collect();
+        // No key triggered them, e.g. for a timeout of nested remaps without timeout_key
+        self.dispatch_actions(&actions, &Key::KEY_RESERVED)
+    }
+
     // Handle EventType::RELATIVE
     fn on_relative_event(
         &mut self,
@@ -272,6 +475,59 @@ impl EventHandler {
         Ok(())
     }
 
+    // Nested remaps and held keys remapped in one scope shouldn't apply to the next key pressed in another.
+    fn flush_on_scope_change(&mut self, config: &Config, device: &InputDeviceInfo) -> Result<(), Box<dyn Error>> {
+        if self.override_remaps.is_empty() && self.pressed_modmaps.is_empty() {
+            return Ok(());
+        }
+        let Some(last_child) = self.last_child else {
+            return Ok(());
+        };
+        if self.is_child() == last_child {
+            return Ok(());
+        }
+        debug!("Focus moved to another scope, flushing nested remaps and held keys");
+        if !self.override_remaps.is_empty() {
+            self.timeout_override()?;
+        }
+        self.flush_held_keys(config, device);
+        Ok(())
+    }
+
+    // Releases held keys that the current scope would remap differently, and drops multi-purpose keys
+    // still waiting for their alone timeout. Keys with press/release actions finish as they started.
+    fn flush_held_keys(&mut self, config: &Config, device: &InputDeviceInfo) {
+        let held_keys: Vec<Key> = self.pressed_modmaps.keys().copied().collect();
+        let mut released_keys = vec![];
+        for key in held_keys {
+            let pressed_modmap = self.pressed_modmaps[&key].clone();
+            if let Some(ModmapAction::PressReleaseKey(_)) = pressed_modmap {
+                continue;
+            }
+            if same_output(&pressed_modmap, &self.find_modmap(config, &key, device)) {
+                continue;
+            }
+            self.pressed_modmaps.remove(&key);
+            self.flushed_keys.insert(key);
+            if let Some(state) = self.multi_purpose_keys.remove(&key) {
+                released_keys.extend(state.pressed_keys());
+            } else if let Some(original_key) = self.pressed_keys.remove(&key) {
+                released_keys.push(original_key);
+            }
+        }
+
+        released_keys.sort_by(modifiers_last);
+        for key in released_keys {
+            if config.virtual_modifiers.contains(&key) {
+                self.update_modifier(key, RELEASE);
+                continue;
+            } else if MODIFIER_KEYS.contains(&key) {
+                self.update_modifier(key, RELEASE);
+            }
+            self.send_key(&key, RELEASE);
+        }
+    }
+
     fn timeout_override(&mut self) -> Result<(), Box<dyn Error>> {
         if let Some(key) = self.override_timeout_key {
             self.send_key(&key, PRESS);
@@ -415,15 +671,8 @@ impl EventHandler {
     fn find_modmap(&mut self, config: &Config, key: &Key, device: &InputDeviceInfo) -> Option<ModmapAction> {
         for modmap in &config.modmap {
             if let Some(key_action) = modmap.remap.get(key) {
-                if let Some(window_matcher) = &modmap.window {
-                    if !self.match_window(window_matcher) {
-                        continue;
-                    }
-                }
-                if let Some(application_matcher) = &modmap.application {
-                    if !self.match_application(application_matcher) {
-                        continue;
-                    }
+                if !self.matches(&modmap.window_matchers()) {
+                    continue;
                 }
                 if let Some(device_matcher) = &modmap.device {
                     if !self.match_device(device_matcher, device) {
@@ -498,16 +747,8 @@ impl EventHandler {
                     if (exact_match && extra_modifiers.len() > 0) || missing_modifiers.len() > 0 {
                         continue;
                     }
-                    if let Some(window_matcher) = &entry.title {
-                        if !self.match_window(window_matcher) {
-                            continue;
-                        }
-                    }
-
-                    if let Some(application_matcher) = &entry.application {
-                        if !self.match_application(application_matcher) {
-                            continue;
-                        }
+                    if !self.matches(&entry.window_matchers()) {
+                        continue;
                     }
                     if let Some(device_matcher) = &entry.device {
                         if !self.match_device(device_matcher, device) {
@@ -696,14 +937,14 @@ impl EventHandler {
         false
     }
 
-    fn match_application(&mut self, application_matcher: &OnlyOrNot) -> bool {
+    fn current_application(&mut self) -> &str {
         // Lazily fill the wm_class cache
-        if self.application_cache.is_none() {
-            match self.application_client.current_application() {
-                Some(application) => self.application_cache = Some(application),
-                None => self.application_cache = Some(String::new()),
-            }
-        }
+        self.application_cache
+            .get_or_insert_with(|| self.application_client.current_application().unwrap_or_default())
+    }
+
+    fn match_application(&mut self, application_matcher: &OnlyOrNot) -> bool {
+        self.current_application();
 
         if let Some(application) = &self.application_cache {
             if let Some(application_only) = &application_matcher.only {
@@ -716,6 +957,147 @@ impl EventHandler {
         false
     }
 
+    fn match_resource_name(&mut self, resource_name_matcher: &OnlyOrNot) -> bool {
+        // Lazily fill the resource name cache
+        let resource_name = self
+            .resource_name_cache
+            .get_or_insert_with(|| self.application_client.current_resource_name().unwrap_or_default());
+        match_attributes(resource_name_matcher, std::slice::from_ref(resource_name))
+    }
+
+    fn window_properties(&mut self) -> &WindowProperties {
+        // Lazily fill the window properties cache
+        if self.window_properties_cache.is_none() {
+            let properties = self.application_client.current_window_properties().unwrap_or_default();
+            debug!("Window properties: {properties:?}");
+            self.window_properties_cache = Some(properties);
+        }
+        self.window_properties_cache.as_ref().unwrap()
+    }
+
+    fn match_workspace(&mut self, workspace_matcher: &OnlyOrNot) -> bool {
+        let workspace = self.window_properties().workspace.as_slice();
+        match_attributes(workspace_matcher, workspace)
+    }
+
+    fn match_output(&mut self, output_matcher: &OnlyOrNot) -> bool {
+        let output = self.window_properties().output.as_slice();
+        match_attributes(output_matcher, output)
+    }
+
+    fn match_marks(&mut self, marks_matcher: &OnlyOrNot) -> bool {
+        let marks = &self.window_properties().marks;
+        match_attributes(marks_matcher, marks)
+    }
+
+    fn current_pid(&mut self) -> Option<i32> {
+        // Lazily fill the PID cache
+        if self.pid_cache.is_none() {
+            let pid = self
+                .application_client
+                .current_pid()
+                .map(|pid| self.resolve_pid(pid as i32));
+            debug!("Window PID: {pid:?}");
+            self.pid_cache = Some(pid);
+        }
+        self.pid_cache.flatten()
+    }
+
+    fn resolve_pid(&mut self, pid: i32) -> i32 {
+        let application = self.current_application().to_string();
+        if let Some((reported, resolved_application, key)) = &self.resolved_pid {
+            if *reported == pid && *resolved_application == application && key.is_alive() {
+                return key.pid;
+            }
+        }
+        let resolved = self.proc_cache.resolve_pid(pid);
+        self.resolved_pid = ProcKey::of(resolved).map(|(key, _)| (pid, application, key));
+        resolved
+    }
+
+    fn match_process(&mut self, process_matcher: &OnlyOrNot) -> bool {
+        // Lazily fill the process cache
+        if self.process_cache.is_none() {
+            match self.current_pid() {
+                Some(pid) => self.process_cache = Some(process_attributes(pid)),
+                None => self.process_cache = Some(vec![]),
+            }
+        }
+
+        match &self.process_cache {
+            Some(attributes) => match_attributes(process_matcher, attributes),
+            None => false,
+        }
+    }
+
+    fn match_foreground_process(&mut self, process_matcher: &OnlyOrNot) -> bool {
+        // Lazily fill the foreground process cache
+        if self.foreground_process_cache.is_none() {
+            let attributes = match self.current_pid() {
+                Some(pid) => foreground_processes(pid)
+                    .into_iter()
+                    .flat_map(process_attributes)
+                    .collect(),
+                None => vec![],
+            };
+            debug!("Foreground processes: {attributes:?}");
+            self.foreground_process_cache = Some(attributes);
+        }
+
+        match &self.foreground_process_cache {
+            Some(attributes) => match_attributes(process_matcher, attributes),
+            None => false,
+        }
+    }
+
+    fn match_cgroup(&mut self, cgroup_matcher: &OnlyOrNot) -> bool {
+        // Lazily fill the cgroup cache
+        if self.cgroup_cache.is_none() {
+            let mut attributes = vec![];
+            if let Some(path) = self.current_pid().and_then(cgroup_of) {
+                debug!("Window cgroup: {path}");
+                let components = path.split('/').filter(|component| !component.is_empty());
+                attributes.extend(components.map(String::from));
+                attributes.push(path);
+            }
+            self.cgroup_cache = Some(attributes);
+        }
+
+        match &self.cgroup_cache {
+            Some(attributes) => match_attributes(cgroup_matcher, attributes),
+            None => false,
+        }
+    }
+
+    fn match_scope(&mut self, scope: Scope) -> bool {
+        match scope {
+            Scope::Child => self.is_child(),
+            Scope::Outside => !self.is_child(),
+            Scope::Global => true,
+        }
+    }
+
+    fn is_child(&mut self) -> bool {
+        // Lazily fill the ancestry cache
+        if let Some(is_child) = self.child_cache {
+            return is_child;
+        }
+        let is_child = if let Some(pid) = self.current_pid() {
+            if self.proc_cache.is_child_or_grandchild(pid) {
+                debug!("Window PID is a child of the current process");
+                true
+            } else {
+                debug!("Window PID is not a child of the current process");
+                false
+            }
+        } else {
+            debug!("Could not retrieve window PID");
+            false
+        };
+        self.child_cache = Some(is_child);
+        is_child
+    }
+
     fn match_device(&self, device_matcher: &config::device::Device, device: &InputDeviceInfo) -> bool {
         if let Some(device_only) = &device_matcher.only {
             return device_only.iter().any(|m| device.matches(m));
@@ -752,6 +1134,17 @@ fn is_remap(actions: &Vec<KeymapAction>) -> bool {
     })
 }
 
+// A matcher matches when any of the attributes matches
+fn match_attributes(matcher: &OnlyOrNot, attributes: &[String]) -> bool {
+    if let Some(only) = &matcher.only {
+        return only.iter().any(|m| attributes.iter().any(|a| m.matches(a)));
+    }
+    if let Some(not) = &matcher.not {
+        return not.iter().all(|m| attributes.iter().all(|a| !m.matches(a)));
+    }
+    false
+}
+
 fn with_extra_modifiers(
     actions: &Vec<KeymapAction>,
     extra_modifiers: &Vec<Key>,
@@ -813,6 +1206,19 @@ lazy_static! {
 
 // ---
 
+// Whether two modmaps send the same keys for a held key
+fn same_output(a: &Option<ModmapAction>, b: &Option<ModmapAction>) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(ModmapAction::Key(a)), Some(ModmapAction::Key(b))) => a == b,
+        (Some(ModmapAction::MultiPurposeKey(a)), Some(ModmapAction::MultiPurposeKey(b))) => {
+            a.held.clone().into_vec() == b.held.clone().into_vec()
+                && a.alone.clone().into_vec() == b.alone.clone().into_vec()
+        }
+        _ => false,
+    }
+}
+
 fn is_pressed(value: i32) -> bool {
     value == PRESS || value == REPEAT
 }
@@ -882,6 +1288,15 @@ impl MultiPurposeKeyState {
         }
     }
 
+    // Held keys that were pressed. Nothing was sent while waiting for the alone timeout.
+    fn pressed_keys(&self) -> Vec<Key> {
+        if self.alone_timeout_at.is_some() {
+            vec![]
+        } else {
+            self.held.clone().into_vec()
+        }
+    }
+
     fn force_held(&mut self) -> Vec<(Key, i32)> {
         if self.alone_timeout_at.is_some() {
             self.alone_timeout_at = None;
@@ -1,11 +1,12 @@
 use crate::config::Config;
-use crate::device::{device_watcher, get_input_devices, output_device};
+use crate::device::{device_watcher, get_input_devices, other_instance_filters, output_device};
 use crate::event_handler::EventHandler;
 use action_dispatcher::ActionDispatcher;
 use anyhow::{anyhow, bail, Context};
+use clap::builder::ArgPredicate;
 use clap::{CommandFactory, Parser, ValueEnum};
 use clap_complete::Shell;
-use client::build_client;
+use client::{build_client, ClientKind};
 use config::{config_watcher, load_configs};
 use device::InputDevice;
 use event::Event;
@@ -13,15 +14,16 @@ use nix::libc::ENODEV;
 use nix::sys::inotify::{AddWatchFlags, Inotify, InotifyEvent};
 use nix::sys::select::select;
 use nix::sys::select::FdSet;
-use nix::sys::signal;
-use nix::sys::signalfd::{SfdFlags, SigSet, SignalFd};
 use nix::sys::timerfd::{ClockId, TimerFd, TimerFlags};
+use proc_cache::ScopeStrategy;
+use regex::Regex;
 use std::collections::HashMap;
 use std::io::stdout;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
-use std::process::Command;
+use std::process::ExitCode;
 use std::time::Duration;
+use supervisor::{set_child_subreaper, Supervisor};
 
 mod action;
 mod action_dispatcher;
@@ -31,6 +33,7 @@ mod device;
 mod event;
 mod event_handler;
 mod proc_cache;
+mod supervisor;
 #[cfg(test)]
 mod tests;
 
@@ -46,6 +49,10 @@ struct Args {
     /// Match mice by default
     #[arg(long)]
     mouse: bool,
+    /// Read the output devices of the xremap instances already running instead of grabbing keyboards and mice,
+    /// e.g. to run one xremap per application
+    #[arg(long, conflicts_with_all = ["device", "mouse"], verbatim_doc_comment)]
+    chain: bool,
     /// Targets to watch
     #[arg(long, value_enum, num_args = 0.., value_delimiter = ',', require_equals = true,
            default_missing_value = "device", verbatim_doc_comment)]
@@ -64,8 +71,31 @@ struct Args {
     vendor: Option<String>,
     #[arg(long)]
     product: Option<String>,
+    /// Keep running until every descendant of the command has exited
+    #[arg(long, conflicts_with_all = ["pid", "pid_from_name"])]
+    wait_descendants: bool,
+    /// Remap windows of an already running process and its descendants instead of running a command
+    #[arg(long, value_name = "PID", conflicts_with = "args")]
+    pid: Vec<i32>,
+    /// Remap windows of already running processes whose name or executable matches a regex
+    #[arg(long, value_name = "REGEX", conflicts_with = "args")]
+    pid_from_name: Option<String>,
+    /// How to tell whether a window belongs to the command, cgroup by default with --cgroup
+    #[arg(long, value_enum, default_value_t = ScopeStrategy::Ancestry, default_value_if("cgroup", ArgPredicate::IsPresent, "cgroup"))]
+    scope_strategy: ScopeStrategy,
+    /// Cgroup path or systemd unit of the command, implying --scope-strategy cgroup,
+    /// e.g. /user.slice/user-1000.slice/user@1000.service/app.slice or app-firefox-1234.scope
+    #[arg(long, value_name = "CGROUP", verbatim_doc_comment)]
+    cgroup: Option<String>,
+    /// Window manager client to get the focused window from, detected from the environment by default
+    #[arg(long, value_enum)]
+    client: Option<ClientKind>,
+    /// Unix socket to create, or FIFO to read, for focus information as JSON lines from another program,
+    /// e.g. {"application": "firefox", "title": "Mozilla Firefox", "pid": 1234}
+    #[arg(long, value_name = "PATH", verbatim_doc_comment)]
+    focus_input: Option<PathBuf>,
     /// Command and arguments.
-    #[clap(required_unless_present = "completions", num_args = 1..)]
+    #[clap(required_unless_present_any = ["completions", "pid", "pid_from_name"], num_args = 1..)]
     args: Vec<String>,
 }
 
@@ -83,24 +113,35 @@ enum ReloadEvent {
     ReloadDevices,
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<ExitCode> {
     env_logger::init();
 
     let Args {
-        device: device_filter,
+        device: mut device_filter,
         ignore: ignore_filter,
         mouse,
+        chain,
         watch,
         config,
         completions,
         product,
         vendor,
+        wait_descendants,
+        pid,
+        pid_from_name,
+        scope_strategy,
+        cgroup,
+        client,
+        focus_input,
         args,
     } = Args::parse();
 
     if let Some(shell) = completions {
         clap_complete::generate(shell, &mut Args::command(), "xremap", &mut stdout());
-        return Ok(());
+        return Ok(ExitCode::SUCCESS);
+    }
+    if cgroup.is_some() && scope_strategy != ScopeStrategy::Cgroup {
+        bail!("--cgroup only works with --scope-strategy cgroup");
     }
 
     // Configuration
@@ -121,6 +162,16 @@ fn main() -> anyhow::Result<()> {
             e
         ),
     };
+    let pid_from_name = match pid_from_name.as_deref().map(Regex::new).transpose() {
+        Ok(pid_from_name) => pid_from_name,
+        Err(e) => bail!("Invalid --pid-from-name regex: {}", e),
+    };
+    if chain {
+        device_filter = other_instance_filters().context("Failed to list input devices")?;
+        if device_filter.is_empty() {
+            bail!("--chain found no other xremap instance");
+        }
+    }
     let watch_devices = watch.contains(&WatchTargets::Device);
     let watch_config = watch.contains(&WatchTargets::Config);
 
@@ -135,7 +186,6 @@ fn main() -> anyhow::Result<()> {
     let device_watcher = device_watcher(watch_devices).context("Setting up device watcher")?;
     let config_watcher = config_watcher(watch_config, &config_paths).context("Setting up config watcher")?;
     let watchers: Vec<_> = device_watcher.iter().chain(config_watcher.iter()).collect();
-    let mut handler = EventHandler::new(timer, &config.default_mode, delay, build_client());
     let vendor = u16::from_str_radix(vendor.unwrap_or_default().trim_start_matches("0x"), 16).unwrap_or(0x1234);
     let product = u16::from_str_radix(product.unwrap_or_default().trim_start_matches("0x"), 16).unwrap_or(0x5678);
     let output_device =
@@ -143,28 +193,35 @@ fn main() -> anyhow::Result<()> {
             Ok(output_device) => output_device,
             Err(e) => bail!("Failed to prepare an output device: {}", e),
         };
-    let mut dispatcher = ActionDispatcher::new(output_device);
+    let application_client = build_client(client, focus_input)?;
 
-    // Run child process
-    let mut child = Command::new(&args[0]).args(&args[1..]).spawn()?;
-
-    let sfd = {
-        let mut mask = SigSet::empty();
-        mask.add(signal::SIGCHLD);
-        mask.thread_block().unwrap();
-
-        SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK).unwrap()
+    // Run child process, or attach to running ones
+    let mut supervisor = if args.is_empty() {
+        Supervisor::attach(&pid, pid_from_name.as_ref(), scope_strategy, cgroup)?
+    } else {
+        if let Err(e) = set_child_subreaper() {
+            println!("Failed to become a child subreaper, double-forked descendants won't be remapped: {e}");
+        }
+        Supervisor::spawn(&args, wait_descendants, scope_strategy, cgroup)?
     };
+    let mut dispatcher = ActionDispatcher::new(output_device, supervisor.launched_sessions());
+    let proc_cache = supervisor.proc_cache();
+    let mut handler = EventHandler::new(timer, &config.default_mode, delay, application_client, proc_cache);
 
     // Main loop
     'main_loop: loop {
         match 'event_loop: loop {
-            match child.try_wait()? {
-                Some(_) => break 'main_loop,
-                None => {}
+            let focus_fd = handler.focus_fd();
+            let readable_fds = select_readable(input_devices.values(), &watchers, timer_fd, focus_fd, &supervisor)?;
+            if supervisor.on_readable(&readable_fds)? {
+                break 'main_loop;
+            }
+            if focus_fd.is_some_and(|focus_fd| readable_fds.contains(focus_fd)) {
+                let events = vec![Event::FocusChange];
+                if let Err(error) = handle_events(&mut handler, &mut dispatcher, &mut config, events) {
+                    println!("Error on focus change: {error}")
+                }
             }
-
-            let readable_fds = select_readable(input_devices.values(), &watchers, timer_fd, &sfd)?;
             if readable_fds.contains(timer_fd) {
                 if let Err(error) =
                     handle_events(&mut handler, &mut dispatcher, &mut config, vec![Event::OverrideTimeout])
@@ -218,29 +275,40 @@ fn main() -> anyhow::Result<()> {
                 if let Ok(c) = load_configs(&config_paths) {
                     println!("Reloading Config");
                     config = c;
+                    if let Err(error) =
+                        handle_events(&mut handler, &mut dispatcher, &mut config, vec![Event::ConfigReload])
+                    {
+                        println!("Error on config reload: {error}")
+                    }
                 }
             }
         }
     }
 
-    Ok(())
+    Ok(supervisor.exit_code())
 }
 
 fn select_readable<'a>(
     devices: impl Iterator<Item = &'a InputDevice>,
     watchers: &[&Inotify],
     timer_fd: RawFd,
-    signal_fd: &SignalFd,
+    focus_fd: Option<RawFd>,
+    supervisor: &Supervisor,
 ) -> anyhow::Result<FdSet> {
     let mut read_fds = FdSet::new();
     read_fds.insert(timer_fd);
+    if let Some(focus_fd) = focus_fd {
+        read_fds.insert(focus_fd);
+    }
     for device in devices {
         read_fds.insert(device.as_raw_fd());
     }
     for inotify in watchers {
         read_fds.insert(inotify.as_raw_fd());
     }
-    read_fds.insert(signal_fd.as_raw_fd());
+    for fd in supervisor.fds() {
+        read_fds.insert(fd);
+    }
     select(None, &mut read_fds, None, None, None)?;
     Ok(read_fds)
 }
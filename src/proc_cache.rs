@@ -1,57 +1,453 @@
-use procfs::process::all_processes;
+use crate::supervisor::LaunchedSessions;
+use clap::ValueEnum;
+use procfs::process::{all_processes, Process, Stat};
 use std::collections::HashMap;
+use std::ffi::OsStr;
+
+// Set for the wrapped command, and inherited by its processes, to recognize them with ScopeStrategy::Environ
+pub const SCOPE_ENV: &str = "XREMAP_SCOPE";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScopeStrategy {
+    /// windows of processes descending from the command
+    Ancestry,
+    /// windows of processes whose environment has the XREMAP_SCOPE of the command,
+    /// falling back to ancestry when the environment can't be read
+    Environ,
+    /// windows of processes in the cgroup of --cgroup, or in a transient systemd scope the command is run in
+    Cgroup,
+}
+
+// A pid alone is not enough to identify a process because the kernel recycles pids.
+// The start time (in clock ticks since boot) never repeats for the same pid, so the pair does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ProcKey {
+    pub pid: i32,
+    pub start_time: u64,
+}
+
+impl ProcKey {
+    // Read the current identity of a pid, or None if no such process exists.
+    pub fn of(pid: i32) -> Option<(ProcKey, Stat)> {
+        let stat = Process::new(pid).and_then(|process| process.stat()).ok()?;
+        let key = ProcKey {
+            pid,
+            start_time: stat.starttime,
+        };
+        Some((key, stat))
+    }
+
+    pub fn is_alive(&self) -> bool {
+        ProcKey::of(self.pid).is_some_and(|(key, _)| key == *self)
+    }
+}
+
+// What `process` matchers are matched against: the exe, comm, cmdline and cwd of a process.
+// Attributes that can't be read, such as the exe of another user's process, are skipped.
+pub fn process_attributes(pid: i32) -> Vec<String> {
+    let Ok(process) = Process::new(pid) else {
+        return vec![];
+    };
+    let mut attributes = vec![];
+    if let Ok(exe) = process.exe() {
+        attributes.push(exe.to_string_lossy().into_owned());
+    }
+    if let Ok(stat) = process.stat() {
+        attributes.push(stat.comm);
+    }
+    if let Ok(cmdline) = process.cmdline() {
+        if !cmdline.is_empty() {
+            attributes.push(cmdline.join(" "));
+        }
+    }
+    if let Ok(cwd) = process.cwd() {
+        attributes.push(cwd.to_string_lossy().into_owned());
+    }
+    attributes
+}
+
+// Foreground jobs of the terminals that children of `pid` are attached to, e.g. vim running in a shell
+// of a terminal emulator. Each job is represented by its process group leader if it's still alive.
+// The focused tab is unknown, so there is one job per tab of the terminal emulator.
+pub fn foreground_processes(pid: i32) -> Vec<i32> {
+    let Ok(processes) = all_processes() else {
+        return vec![];
+    };
+    let stats: Vec<Stat> = processes.flatten().filter_map(|process| process.stat().ok()).collect();
+
+    let mut foreground_pgrps: Vec<i32> = stats
+        .iter()
+        .filter(|stat| stat.ppid == pid && stat.tty_nr != 0 && stat.tpgid > 0)
+        .map(|stat| stat.tpgid)
+        .collect();
+    foreground_pgrps.sort_unstable();
+    foreground_pgrps.dedup();
+
+    foreground_pgrps
+        .into_iter()
+        .filter_map(|pgrp| {
+            let members: Vec<&Stat> = stats.iter().filter(|stat| stat.pgrp == pgrp).collect();
+            members
+                .iter()
+                .find(|stat| stat.pid == pgrp)
+                .or(members.first())
+                .map(|stat| stat.pid)
+        })
+        .collect()
+}
+
+// The cgroup of a process as systemd sees it, e.g. /user.slice/user-1000.slice/user@1000.service/app.slice/app-firefox-1234.scope
+pub fn cgroup_of(pid: i32) -> Option<String> {
+    let cgroups = Process::new(pid).and_then(|process| process.cgroups()).ok()?.0;
+    // Prefer the systemd hierarchy of a hybrid cgroup v1 setup over the unified one
+    let cgroup = cgroups
+        .iter()
+        .find(|cgroup| cgroup.controllers.iter().any(|controller| controller == "name=systemd"))
+        .or_else(|| cgroups.iter().find(|cgroup| cgroup.hierarchy == 0))?;
+    Some(cgroup.pathname.clone())
+}
+
+// Whether a cgroup path is `cgroup` or below it. `cgroup` may also be the name of a systemd unit,
+// such as app-firefox-1234.scope, to match any of the path's components.
+pub fn is_in_cgroup(path: &str, cgroup: &str) -> bool {
+    if cgroup.starts_with('/') {
+        let cgroup = cgroup.trim_end_matches('/');
+        path == cgroup || path.strip_prefix(cgroup).is_some_and(|rest| rest.starts_with('/'))
+    } else {
+        path.split('/').any(|component| component == cgroup)
+    }
+}
+
+// The pid of a process in its innermost PID namespace, from the text of /proc/<pid>/status,
+// or None if it is in the host's namespace. NSpid lists the pid in each nested namespace, from the host's one.
+fn namespaced_pid(status: &str) -> Option<i32> {
+    let nspid: Vec<i32> = status
+        .lines()
+        .find_map(|line| line.strip_prefix("NSpid:"))?
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .ok()?;
+    match nspid[..] {
+        [_, .., innermost] => Some(innermost),
+        _ => None,
+    }
+}
 
 pub struct ProcCache {
-    parent_map: HashMap<i32, i32>,
-    result_cache: HashMap<i32, bool>,
-    my_pid: i32,
+    // Whether a process is in scope. Entries of dead processes are evicted on cache misses.
+    result_cache: HashMap<ProcKey, bool>,
+    roots: Vec<ProcKey>,
+    // XREMAP_SCOPE of the wrapped command with ScopeStrategy::Environ
+    scope_token: Option<String>,
+    // Cgroup path or systemd unit of the supervised processes with ScopeStrategy::Cgroup
+    cgroup: Option<String>,
+    // Sessions of commands run by `launch`, which are not in scope even though xremap adopts them
+    launched_sessions: LaunchedSessions,
 }
 
 impl ProcCache {
+    // Track the process tree of xremap itself, i.e. the wrapped command and its adopted descendants
     pub fn new() -> Self {
+        let pid = std::process::id() as i32;
+        let root = match ProcKey::of(pid) {
+            Some((key, _)) => key,
+            None => ProcKey { pid, start_time: 0 },
+        };
+        Self::with_roots(vec![root])
+    }
+
+    // Track the processes of the wrapped command by their XREMAP_SCOPE
+    pub fn with_scope_token(scope_token: String) -> Self {
+        Self {
+            scope_token: Some(scope_token),
+            ..Self::new()
+        }
+    }
+
+    // Track the processes in a cgroup, regardless of their ancestry
+    pub fn with_cgroup(cgroup: String) -> Self {
+        Self {
+            cgroup: Some(cgroup),
+            ..Self::with_roots(vec![])
+        }
+    }
+
+    // Track the process trees of already running processes
+    pub fn with_roots(roots: Vec<ProcKey>) -> Self {
         Self {
-            parent_map: HashMap::new(),
             result_cache: HashMap::new(),
-            my_pid: std::process::id() as i32,
+            roots,
+            scope_token: None,
+            cgroup: None,
+            launched_sessions: LaunchedSessions::default(),
+        }
+    }
+
+    pub fn with_launched_sessions(self, launched_sessions: LaunchedSessions) -> Self {
+        Self {
+            launched_sessions,
+            ..self
         }
     }
 
-    fn update_cache(&mut self) -> Result<(), procfs::ProcError> {
-        let all_procs = all_processes()?;
-        self.parent_map.clear();
-        self.result_cache.clear();
+    fn evict_dead(&mut self) {
+        self.result_cache.retain(|key, _| key.is_alive());
+    }
+
+    // Walk up the parents of `key` one /proc/<pid>/stat at a time, stopping at the first ancestor
+    // whose result is already known.
+    fn descends_from_roots(&self, key: ProcKey, stat: Stat) -> bool {
+        let (mut current, mut stat) = (key, stat);
+        loop {
+            if self.roots.contains(&current) {
+                return true;
+            }
+            if let Some(&result) = self.result_cache.get(&current) {
+                return result;
+            }
+            // Commands run by `launch` are adopted by xremap but don't belong to the wrapped command
+            if stat.ppid == 0 || self.launched_sessions.contains(stat.session) {
+                return false;
+            }
+            let Some((parent, parent_stat)) = ProcKey::of(stat.ppid) else {
+                return false;
+            };
+            // The parent exited and its pid was reused while we were walking the tree
+            if parent.start_time > current.start_time {
+                return false;
+            }
+            (current, stat) = (parent, parent_stat);
+        }
+    }
+
+    // Whether the environment of a process has the scope token, or None if it's unknown
+    fn has_scope_token(&self, pid: i32) -> Option<bool> {
+        let scope_token = self.scope_token.as_ref()?;
+        let environ = Process::new(pid).and_then(|process| process.environ()).ok()?;
+        Some(
+            environ
+                .get(OsStr::new(SCOPE_ENV))
+                .is_some_and(|value| value == scope_token.as_str()),
+        )
+    }
 
-        for proc in all_procs {
-            if let Ok(stat) = proc.and_then(|x| x.stat()) {
-                self.parent_map.insert(stat.pid, stat.ppid);
+    // Translate a pid from the PID namespace of a sandbox, such as Flatpak or bubblewrap, to the host's.
+    // Only the trees of the roots are searched for it. It is a host pid when no sandboxed process has it,
+    // which is checked first since the host pid of an unrelated process may be the same.
+    pub fn resolve_pid(&mut self, pid: i32) -> i32 {
+        let mut pending: Vec<i32> = self.roots.iter().map(|root| root.pid).collect();
+        while let Some(current) = pending.pop() {
+            let Ok(process) = Process::new(current) else {
+                continue;
+            };
+            let status = std::fs::read_to_string(format!("/proc/{current}/status")).unwrap_or_default();
+            if namespaced_pid(&status) == Some(pid) {
+                log::debug!("PID {pid} in a PID namespace is {current}");
+                return current;
+            }
+            for task in process.tasks().into_iter().flatten().flatten() {
+                pending.extend(
+                    task.children()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|child| child as i32),
+                );
             }
         }
-        Ok(())
+        pid
     }
 
+    // The root processes themselves are considered part of their own trees.
     pub fn is_child_or_grandchild(&mut self, target_pid: i32) -> bool {
-        if let Some(&result) = self.result_cache.get(&target_pid) {
+        let Some((key, stat)) = ProcKey::of(target_pid) else {
+            return false;
+        };
+        if let Some(&result) = self.result_cache.get(&key) {
             return result;
+        }
+
+        self.evict_dead();
+        let result = if let Some(cgroup) = &self.cgroup {
+            cgroup_of(target_pid).is_some_and(|path| is_in_cgroup(&path, cgroup))
+        } else if let Some(result) = self.has_scope_token(target_pid) {
+            result
         } else {
-            if let Err(err) = self.update_cache() {
-                log::error!("Could not update process cache: {err}");
-            }
+            self.descends_from_roots(key, stat)
+        };
+        self.result_cache.insert(key, result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::pty::openpty;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_child_is_in_scope() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let mut cache = ProcCache::new();
+        assert!(cache.is_child_or_grandchild(child.id() as i32));
+        assert!(cache.is_child_or_grandchild(std::process::id() as i32));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_launched_session_is_out_of_scope() {
+        let mut child = Command::new("sleep");
+        child.arg("10");
+        unsafe {
+            child.pre_exec(|| {
+                nix::unistd::setsid()?;
+                Ok(())
+            });
         }
+        let mut child = child.spawn().unwrap();
+        let launched_sessions = LaunchedSessions::default();
+        launched_sessions.register(child.id() as i32);
+        let mut cache = ProcCache::new().with_launched_sessions(launched_sessions);
+        assert!(!cache.is_child_or_grandchild(child.id() as i32));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
 
-        let mut current_pid = target_pid;
+    #[test]
+    fn test_attached_process_tree_is_in_scope() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let (key, _) = ProcKey::of(child.id() as i32).unwrap();
+        let mut cache = ProcCache::with_roots(vec![key]);
+        assert!(cache.is_child_or_grandchild(key.pid));
+        assert!(!cache.is_child_or_grandchild(std::process::id() as i32));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
 
-        while let Some(&parent_pid) = self.parent_map.get(&current_pid) {
-            if parent_pid == self.my_pid {
-                self.result_cache.insert(target_pid, true);
-                return true;
-            }
-            if parent_pid == 1 || parent_pid == 0 {
+    #[test]
+    fn test_scope_token() {
+        let child = Command::new("sleep").arg("10").env(SCOPE_ENV, "test").spawn().unwrap();
+        let other_child = Command::new("sleep").arg("10").env(SCOPE_ENV, "other").spawn().unwrap();
+        let mut cache = ProcCache::with_scope_token("test".to_string());
+        assert!(cache.is_child_or_grandchild(child.id() as i32));
+        assert!(!cache.is_child_or_grandchild(other_child.id() as i32));
+        for mut child in [child, other_child] {
+            child.kill().unwrap();
+            child.wait().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_is_in_cgroup() {
+        let path = "/user.slice/user-1000.slice/user@1000.service/app.slice/app-firefox-1234.scope";
+        assert!(is_in_cgroup(path, "/user.slice/user-1000.slice"));
+        assert!(is_in_cgroup(path, "/user.slice/user-1000.slice/"));
+        assert!(is_in_cgroup(path, path));
+        assert!(!is_in_cgroup(path, "/user.slice/user-100"));
+        assert!(is_in_cgroup(path, "app-firefox-1234.scope"));
+        assert!(!is_in_cgroup(path, "app-firefox"));
+    }
+
+    #[test]
+    fn test_cgroup_scope() {
+        let path = cgroup_of(std::process::id() as i32).unwrap();
+        let mut cache = ProcCache::with_cgroup(path);
+        assert!(cache.is_child_or_grandchild(std::process::id() as i32));
+        assert!(!cache.is_child_or_grandchild(i32::MAX));
+    }
+
+    #[test]
+    fn test_namespaced_pid() {
+        let status = |nspid: &str| format!("Name:\tsleep\nTgid:\t4321\nPid:\t4321\nPPid:\t4320\n{nspid}\nNSsid:\t1\n");
+        assert_eq!(namespaced_pid(&status("NSpid:\t4321\t1")), Some(1));
+        // A namespace nested in another sandbox's
+        assert_eq!(namespaced_pid(&status("NSpid:\t4321\t12\t2")), Some(2));
+        assert_eq!(namespaced_pid(&status("NSpid:\t4321")), None);
+        // Kernels older than 4.1 don't report NSpid
+        assert_eq!(namespaced_pid(&status("")), None);
+        assert_eq!(namespaced_pid(&status("NSpid:\t4321\tx")), None);
+    }
+
+    // Needs to create a PID namespace, e.g. with CAP_SYS_ADMIN or user namespaces
+    #[test]
+    #[ignore]
+    fn test_resolve_pid() {
+        // Run sleep as PID 1 of a new PID namespace
+        let mut child = Command::new("unshare")
+            .args(["--pid", "--fork", "--kill-child", "sleep", "10"])
+            .spawn()
+            .unwrap();
+        let mut cache = ProcCache::new();
+        let mut resolved = 1;
+        for _ in 0..100 {
+            resolved = cache.resolve_pid(1);
+            if resolved != 1 {
                 break;
             }
-            current_pid = parent_pid;
+            assert!(child.try_wait().unwrap().is_none(), "unshare failed");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_ne!(resolved, 1);
+        assert!(cache.is_child_or_grandchild(resolved));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(cache.resolve_pid(std::process::id() as i32), std::process::id() as i32);
+    }
+
+    #[test]
+    fn test_unrelated_process_is_out_of_scope() {
+        let mut cache = ProcCache::new();
+        assert!(!cache.is_child_or_grandchild(1));
+        assert!(!cache.is_child_or_grandchild(i32::MAX));
+    }
+
+    #[test]
+    fn test_process_attributes() {
+        let attributes = process_attributes(std::process::id() as i32);
+        let exe = std::env::current_exe().unwrap();
+        assert!(attributes.contains(&exe.to_string_lossy().into_owned()));
+        let cwd = std::env::current_dir().unwrap();
+        assert!(attributes.contains(&cwd.to_string_lossy().into_owned()));
+        assert!(process_attributes(i32::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_foreground_processes() {
+        // A session leader with a controlling terminal is the foreground job of that terminal
+        let pty = openpty(None, None).unwrap();
+        let mut child = Command::new("sleep");
+        child.arg("10").stdin(unsafe { Stdio::from_raw_fd(pty.slave) });
+        unsafe {
+            child.pre_exec(|| {
+                nix::unistd::setsid()?;
+                nix::libc::ioctl(0, nix::libc::TIOCSCTTY, 0);
+                Ok(())
+            });
         }
+        let mut child = child.spawn().unwrap();
+        assert!(foreground_processes(std::process::id() as i32).contains(&(child.id() as i32)));
+        assert!(foreground_processes(i32::MAX).is_empty());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        nix::unistd::close(pty.master).unwrap();
+    }
+
+    #[test]
+    fn test_dead_process_is_evicted() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let (key, _) = ProcKey::of(child.id() as i32).unwrap();
+        let mut cache = ProcCache::new();
+        assert!(cache.is_child_or_grandchild(key.pid));
+        child.kill().unwrap();
+        child.wait().unwrap();
 
-        self.result_cache.insert(target_pid, false);
-        false
+        assert!(!key.is_alive());
+        assert!(!cache.is_child_or_grandchild(key.pid));
+        cache.is_child_or_grandchild(1);
+        assert!(!cache.result_cache.contains_key(&key));
     }
 }
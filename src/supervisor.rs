@@ -0,0 +1,441 @@
+use crate::proc_cache::{ProcCache, ProcKey, ScopeStrategy, SCOPE_ENV};
+use anyhow::bail;
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::select::FdSet;
+use nix::sys::signal::{kill, killpg, SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{isatty, Pid, Uid};
+use procfs::process::{all_processes, Process};
+use regex::Regex;
+use std::cell::RefCell;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitCode};
+use std::rc::Rc;
+
+// Signals forwarded to the command instead of terminating xremap
+const FORWARDED_SIGNALS: [Signal; 4] = [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP, Signal::SIGQUIT];
+
+// Sessions created for commands run by `launch`. They are adopted by xremap as a subreaper
+// just like descendants of the wrapped command, but are not part of it.
+#[derive(Clone, Default)]
+pub struct LaunchedSessions(Rc<RefCell<Vec<i32>>>);
+
+impl LaunchedSessions {
+    pub fn register(&self, sid: i32) {
+        self.0.borrow_mut().push(sid);
+    }
+
+    pub fn contains(&self, sid: i32) -> bool {
+        self.0.borrow().contains(&sid)
+    }
+
+    // Forget sessions without processes left, whose ids may be reused. The session leader is a
+    // helper exiting right away, so a session lasts until the last process of the command is reaped.
+    fn prune(&self) -> anyhow::Result<()> {
+        if self.0.borrow().is_empty() {
+            return Ok(());
+        }
+        let sessions: Vec<i32> = all_processes()?
+            .flatten()
+            .filter_map(|process| process.stat().ok())
+            .map(|stat| stat.session)
+            .collect();
+        self.0.borrow_mut().retain(|sid| sessions.contains(sid));
+        Ok(())
+    }
+}
+
+// Become the reaper of orphaned descendants, so that processes double-forked by the wrapped
+// command are reparented to xremap instead of init and stay in its process tree.
+pub fn set_child_subreaper() -> nix::Result<()> {
+    let result = unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) };
+    Errno::result(result).map(drop)
+}
+
+// Supervise the processes whose windows xremap remaps: either a command wrapped by xremap,
+// or processes that were already running.
+pub struct Supervisor {
+    target: Target,
+    // SIGCHLD and FORWARDED_SIGNALS, blocked so that they are only received through this
+    signal_fd: SignalFd,
+    // Keep running after the command exits until all descendants are gone
+    wait_descendants: bool,
+    // A forwarded signal that could not be delivered, so xremap exits on its own
+    terminated_by: Option<Signal>,
+    // How ProcCache tells whether a window belongs to the supervised processes
+    strategy: ScopeStrategy,
+    // Cgroup path or systemd unit of the supervised processes with ScopeStrategy::Cgroup
+    cgroup: Option<String>,
+    launched_sessions: LaunchedSessions,
+}
+
+enum Target {
+    Command {
+        child: Pid,
+        // Whether the command leads a process group of its own
+        own_group: bool,
+        // Exit status of the wrapped command once it has been reaped
+        status: Option<WaitStatus>,
+    },
+    Attached(Vec<AttachedProcess>),
+}
+
+struct AttachedProcess {
+    key: ProcKey,
+    // Becomes readable when the process exits. None on kernels without pidfd_open(2).
+    pidfd: Option<OwnedFd>,
+}
+
+impl Supervisor {
+    pub fn spawn(
+        args: &[String],
+        wait_descendants: bool,
+        strategy: ScopeStrategy,
+        mut cgroup: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let mut args = args.to_vec();
+        if strategy == ScopeStrategy::Cgroup && cgroup.is_none() {
+            // systemd-run execs the command once the scope is created, so the command keeps its pid
+            let unit = format!("xremap-{}.scope", scope_token());
+            let mut systemd_run = vec!["systemd-run".to_string(), "--scope".to_string()];
+            if !Uid::effective().is_root() {
+                systemd_run.push("--user".to_string());
+            }
+            systemd_run.extend([
+                "--quiet".to_string(),
+                "--collect".to_string(),
+                format!("--unit={unit}"),
+                "--".to_string(),
+            ]);
+            args.splice(0..0, systemd_run);
+            cgroup = Some(unit);
+        }
+
+        // Block signals before spawning so that an early SIGCHLD can't be missed.
+        // The signal mask is reset for the command by std::process.
+        let signal_fd = block_signals()?;
+        let mut command = Command::new(&args[0]);
+        command.args(&args[1..]).env(SCOPE_ENV, scope_token());
+        // Run the command in its own process group so that signals can be forwarded to all of it,
+        // unless it would leave the foreground of the terminal and stop on its first read from it
+        let own_group = !isatty(0).unwrap_or(false);
+        if own_group {
+            command.process_group(0);
+        }
+        let child = command.spawn()?;
+        Ok(Self {
+            target: Target::Command {
+                child: Pid::from_raw(child.id() as i32),
+                own_group,
+                status: None,
+            },
+            signal_fd,
+            wait_descendants,
+            terminated_by: None,
+            strategy,
+            cgroup,
+            launched_sessions: LaunchedSessions::default(),
+        })
+    }
+
+    pub fn attach(
+        pids: &[i32],
+        name: Option<&Regex>,
+        strategy: ScopeStrategy,
+        cgroup: Option<String>,
+    ) -> anyhow::Result<Self> {
+        match strategy {
+            ScopeStrategy::Environ => {
+                println!("warning: attached processes have no {SCOPE_ENV}, falling back to the ancestry scope strategy")
+            }
+            ScopeStrategy::Cgroup if cgroup.is_none() => bail!("--scope-strategy cgroup needs --cgroup with --pid"),
+            _ => {}
+        }
+
+        let mut keys = vec![];
+        for &pid in pids {
+            match ProcKey::of(pid) {
+                Some((key, _)) => keys.push(key),
+                None => bail!("No process with PID {pid}"),
+            }
+        }
+        if let Some(name) = name {
+            let found = find_processes(name)?;
+            if found.is_empty() {
+                bail!("No process matching /{name}/");
+            }
+            keys.extend(found);
+        }
+
+        let processes: Vec<_> = keys
+            .into_iter()
+            .map(|key| {
+                println!("Attaching to PID {}", key.pid);
+                let pidfd = match pidfd_open(key.pid) {
+                    Ok(pidfd) => Some(pidfd),
+                    Err(e) => {
+                        println!("warning: pidfd_open({}) failed, its exit is noticed late: {e}", key.pid);
+                        None
+                    }
+                };
+                AttachedProcess { key, pidfd }
+            })
+            .collect();
+        Ok(Self {
+            target: Target::Attached(processes),
+            signal_fd: block_signals()?,
+            wait_descendants: false,
+            terminated_by: None,
+            strategy,
+            cgroup,
+            launched_sessions: LaunchedSessions::default(),
+        })
+    }
+
+    // Shared with ActionDispatcher, which registers the sessions of commands it launches
+    pub fn launched_sessions(&self) -> LaunchedSessions {
+        self.launched_sessions.clone()
+    }
+
+    // The process cache matching windows of the supervised processes
+    pub fn proc_cache(&self) -> ProcCache {
+        let proc_cache = match (&self.target, self.strategy, &self.cgroup) {
+            (_, ScopeStrategy::Cgroup, Some(cgroup)) => ProcCache::with_cgroup(cgroup.clone()),
+            (Target::Command { .. }, ScopeStrategy::Environ, _) => ProcCache::with_scope_token(scope_token()),
+            (Target::Command { .. }, _, _) => ProcCache::new(),
+            (Target::Attached(processes), _, _) => {
+                ProcCache::with_roots(processes.iter().map(|process| process.key).collect())
+            }
+        };
+        proc_cache.with_launched_sessions(self.launched_sessions.clone())
+    }
+
+    // File descriptors to select(2) on to notice signals and exits of attached processes
+    pub fn fds(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.signal_fd.as_raw_fd()];
+        if let Target::Attached(processes) = &self.target {
+            fds.extend(
+                processes
+                    .iter()
+                    .filter_map(|process| process.pidfd.as_ref().map(AsRawFd::as_raw_fd)),
+            );
+        }
+        fds
+    }
+
+    // Handle readable fds(). Return true once xremap should exit.
+    pub fn on_readable(&mut self, readable_fds: &FdSet) -> anyhow::Result<bool> {
+        let mut child_exited = false;
+        if readable_fds.contains(self.signal_fd.as_raw_fd()) {
+            while let Some(siginfo) = self.signal_fd.read_signal()? {
+                match Signal::try_from(siginfo.ssi_signo as i32) {
+                    Ok(Signal::SIGCHLD) => child_exited = true,
+                    Ok(signal) => self.on_signal(signal, siginfo.ssi_code == libc::SI_KERNEL),
+                    Err(_) => {}
+                }
+            }
+            // Pending SIGCHLDs are merged into one, so reap everything that exited
+            if child_exited {
+                self.reap()?;
+                self.launched_sessions.prune()?;
+            }
+        }
+        if let Target::Attached(processes) = &mut self.target {
+            processes.retain(|process| {
+                if process
+                    .pidfd
+                    .as_ref()
+                    .is_some_and(|fd| !readable_fds.contains(fd.as_raw_fd()))
+                {
+                    return true;
+                }
+                let alive = process.key.is_alive();
+                if !alive {
+                    println!("Attached PID {} exited", process.key.pid);
+                }
+                alive
+            });
+        }
+        self.should_exit(child_exited)
+    }
+
+    // Handle one of FORWARDED_SIGNALS received by xremap. Signals from the kernel, e.g. ^C on the terminal,
+    // are sent to the whole foreground process group.
+    fn on_signal(&mut self, signal: Signal, from_kernel: bool) {
+        match &self.target {
+            // The command is in the foreground process group of xremap, and received it as well
+            Target::Command { own_group: false, .. } if from_kernel => {
+                log::debug!("{signal} was also sent to the command");
+            }
+            Target::Command { child, own_group, .. } => {
+                log::debug!("Forwarding {signal} to the command {child}");
+                let result = if *own_group {
+                    killpg(*child, signal)
+                } else {
+                    kill(*child, signal)
+                };
+                if let Err(e) = result {
+                    log::debug!("Failed to forward {signal}: {e}");
+                    self.terminated_by = Some(signal);
+                }
+            }
+            // Attached processes don't belong to xremap, so only xremap exits
+            Target::Attached(_) => self.terminated_by = Some(signal),
+        }
+    }
+
+    // Exit code of the command, or 128 + signal number like a shell when it was killed by a signal
+    pub fn exit_code(&self) -> ExitCode {
+        let status = match &self.target {
+            Target::Command { status, .. } => *status,
+            Target::Attached(_) => None,
+        };
+        let code = match (status, self.terminated_by) {
+            (Some(WaitStatus::Exited(_, code)), _) => code,
+            (Some(WaitStatus::Signaled(_, signal, _)), _) => 128 + signal as i32,
+            (_, Some(signal)) => 128 + signal as i32,
+            _ => 0,
+        };
+        ExitCode::from(code as u8)
+    }
+
+    // Reap every exited child without blocking: the command, adopted descendants, and launch helpers
+    fn reap(&mut self) -> anyhow::Result<()> {
+        loop {
+            match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => return Ok(()),
+                Ok(exited) => match &mut self.target {
+                    Target::Command { child, status, .. } if exited.pid() == Some(*child) => {
+                        if self.wait_descendants {
+                            println!("Command exited. Waiting for its descendants to exit.");
+                        }
+                        *status = Some(exited);
+                    }
+                    _ => log::debug!("Reaped descendant: {exited:?}"),
+                },
+                Err(Errno::EINTR) => continue,
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+
+    fn should_exit(&self, child_exited: bool) -> anyhow::Result<bool> {
+        if self.terminated_by.is_some() {
+            return Ok(true);
+        }
+        match &self.target {
+            Target::Command { status: None, .. } => Ok(false),
+            // Only look for remaining descendants when one of them may just have exited
+            Target::Command { .. } if self.wait_descendants => {
+                Ok(child_exited && !has_descendants(&self.launched_sessions)?)
+            }
+            Target::Command { .. } => Ok(true),
+            Target::Attached(processes) => Ok(processes.is_empty()),
+        }
+    }
+}
+
+// A token unique to this instance of xremap, since a pid is never reused while its process is running
+fn scope_token() -> String {
+    match ProcKey::of(std::process::id() as i32) {
+        Some((key, _)) => format!("{}-{}", key.pid, key.start_time),
+        None => std::process::id().to_string(),
+    }
+}
+
+fn block_signals() -> nix::Result<SignalFd> {
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGCHLD);
+    for signal in FORWARDED_SIGNALS {
+        mask.add(signal);
+    }
+    mask.thread_block()?;
+    SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK)
+}
+
+// Whether xremap still has children other than commands run by `launch`
+fn has_descendants(launched_sessions: &LaunchedSessions) -> anyhow::Result<bool> {
+    let my_pid = std::process::id() as i32;
+    for process in all_processes()?.flatten() {
+        if let Ok(stat) = process.stat() {
+            if stat.ppid == my_pid && !launched_sessions.contains(stat.session) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+// Only the process name and executable are matched, not the command line, which would also match
+// the sudo or shell that xremap was started from with the pattern among its arguments.
+fn find_processes(name: &Regex) -> anyhow::Result<Vec<ProcKey>> {
+    let ancestors = ancestors();
+    let mut keys = vec![];
+    for process in all_processes()?.flatten() {
+        if ancestors.contains(&process.pid()) {
+            continue;
+        }
+        let Ok(stat) = process.stat() else {
+            continue;
+        };
+        let exe = process.exe().ok();
+        let exe_name = exe
+            .as_ref()
+            .and_then(|exe| exe.file_name())
+            .and_then(|name| name.to_str());
+        if name.is_match(&stat.comm) || exe_name.is_some_and(|exe_name| name.is_match(exe_name)) {
+            keys.push(ProcKey {
+                pid: stat.pid,
+                start_time: stat.starttime,
+            });
+        }
+    }
+    Ok(keys)
+}
+
+// xremap itself and the processes it was started from
+fn ancestors() -> Vec<i32> {
+    let mut pids = vec![];
+    let mut pid = std::process::id() as i32;
+    while pid > 0 && !pids.contains(&pid) {
+        pids.push(pid);
+        match Process::new(pid).and_then(|process| process.stat()) {
+            Ok(stat) => pid = stat.ppid,
+            Err(_) => break,
+        }
+    }
+    pids
+}
+
+fn pidfd_open(pid: i32) -> nix::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    Errno::result(fd).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_processes_ignores_command_line() {
+        let mut child = Command::new("sh")
+            .args(["-c", "sleep 10; : xremap-find-processes-test"])
+            .spawn()
+            .unwrap();
+        let found = find_processes(&Regex::new("xremap-find-processes-test").unwrap()).unwrap();
+        assert!(!found.iter().any(|key| key.pid == child.id() as i32));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_find_processes_ignores_ancestors() {
+        let parent = Process::myself().unwrap().stat().unwrap().ppid;
+        let comm = Process::new(parent).unwrap().stat().unwrap().comm;
+        let found = find_processes(&Regex::new(&format!("^{}$", regex::escape(&comm))).unwrap()).unwrap();
+        assert!(!found.iter().any(|key| key.pid == parent));
+    }
+}
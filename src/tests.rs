@@ -3,20 +3,25 @@ use evdev::InputEvent;
 use evdev::KeyCode as Key;
 use indoc::indoc;
 use nix::sys::timerfd::{ClockId, TimerFd, TimerFlags};
+use std::cell::Cell;
 use std::path::Path;
+use std::rc::Rc;
 use std::time::Duration;
 
-use crate::client::{Client, WMClient};
+use crate::client::{Client, WMClient, WindowProperties};
 use crate::device::InputDeviceInfo;
 use crate::{
     action::Action,
     config::{keymap::build_keymap_table, Config},
     event::{Event, KeyEvent, KeyValue, RelativeEvent},
     event_handler::EventHandler,
+    proc_cache::ProcCache,
 };
 
 struct StaticClient {
     current_application: Option<String>,
+    current_pid: Option<u32>,
+    window_properties: Option<WindowProperties>,
 }
 
 impl Client for StaticClient {
@@ -30,6 +35,36 @@ impl Client for StaticClient {
     fn current_application(&mut self) -> Option<String> {
         self.current_application.clone()
     }
+
+    fn current_pid(&mut self) -> Option<u32> {
+        self.current_pid
+    }
+
+    fn current_window_properties(&mut self) -> Option<WindowProperties> {
+        self.window_properties.clone()
+    }
+}
+
+// A client whose focused window can be changed between events
+struct FocusClient {
+    current_pid: Rc<Cell<Option<u32>>>,
+}
+
+impl Client for FocusClient {
+    fn supported(&mut self) -> bool {
+        true
+    }
+    fn current_window(&mut self) -> Option<String> {
+        None
+    }
+
+    fn current_application(&mut self) -> Option<String> {
+        None
+    }
+
+    fn current_pid(&mut self) -> Option<u32> {
+        self.current_pid.get()
+    }
 }
 
 fn get_input_device_info<'a>() -> InputDeviceInfo<'a> {
@@ -538,6 +573,19 @@ fn test_device_override() {
     );
 }
 
+#[test]
+fn test_device_name_filter() {
+    let device = InputDeviceInfo {
+        name: "xremap pid=1234",
+        path: Path::new("/dev/input/event5"),
+        vendor: 0x1234,
+        product: 0x5678,
+    };
+    assert!(device.matches(&"xremap".to_string()));
+    assert!(!device.matches(&"name:xremap".to_string()));
+    assert!(device.matches(&"name:xremap pid=1234".to_string()));
+}
+
 #[test]
 fn test_merge_remaps() {
     let config = indoc! {"
@@ -776,6 +824,488 @@ fn test_any_key() {
     );
 }
 
+#[test]
+fn test_scope() {
+    let config = indoc! {"
+        modmap:
+          - name: child
+            remap:
+              a: b
+          - name: outside
+            scope: outside
+            remap:
+              a: c
+          - name: global
+            scope: global
+            remap:
+              d: e
+    "};
+
+    assert_actions(
+        config,
+        vec![
+            Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+            Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_D, KeyValue::Press)),
+        ],
+        vec![
+            Action::KeyEvent(KeyEvent::new(Key::KEY_B, KeyValue::Press)),
+            Action::KeyEvent(KeyEvent::new(Key::KEY_E, KeyValue::Press)),
+        ],
+    );
+
+    // The parent of the test process is never its descendant
+    for current_pid in [Some(std::os::unix::process::parent_id()), None] {
+        assert_actions_with_current_pid(
+            config,
+            current_pid,
+            vec![
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_D, KeyValue::Press)),
+            ],
+            vec![
+                Action::KeyEvent(KeyEvent::new(Key::KEY_C, KeyValue::Press)),
+                Action::KeyEvent(KeyEvent::new(Key::KEY_E, KeyValue::Press)),
+            ],
+        );
+    }
+}
+
+#[test]
+fn test_process() {
+    let config = indoc! {"
+        modmap:
+          - process:
+              only: /xremap/
+            scope: global
+            remap:
+              a: b
+          - process:
+              not: /xremap/
+            scope: global
+            remap:
+              c: d
+    "};
+
+    // The test binary is named xremap-<hash>
+    assert_actions(
+        config,
+        vec![
+            Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+            Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_C, KeyValue::Press)),
+        ],
+        vec![
+            Action::KeyEvent(KeyEvent::new(Key::KEY_B, KeyValue::Press)),
+            Action::KeyEvent(KeyEvent::new(Key::KEY_C, KeyValue::Press)),
+        ],
+    );
+
+    assert_actions_with_current_pid(
+        config,
+        None,
+        vec![
+            Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+            Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_C, KeyValue::Press)),
+        ],
+        vec![
+            Action::KeyEvent(KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+            Action::KeyEvent(KeyEvent::new(Key::KEY_D, KeyValue::Press)),
+        ],
+    );
+}
+
+#[test]
+fn test_cgroup() {
+    let config = indoc! {"
+        modmap:
+          - cgroup:
+              only: /^\\//
+            scope: global
+            remap:
+              a: b
+    "};
+
+    assert_actions(
+        config,
+        vec![Event::KeyEvent(
+            get_input_device_info(),
+            KeyEvent::new(Key::KEY_A, KeyValue::Press),
+        )],
+        vec![Action::KeyEvent(KeyEvent::new(Key::KEY_B, KeyValue::Press))],
+    );
+
+    // Windows without a PID have no cgroup
+    assert_actions_with_current_pid(
+        config,
+        None,
+        vec![Event::KeyEvent(
+            get_input_device_info(),
+            KeyEvent::new(Key::KEY_A, KeyValue::Press),
+        )],
+        vec![Action::KeyEvent(KeyEvent::new(Key::KEY_A, KeyValue::Press))],
+    );
+}
+
+#[test]
+fn test_release_in_scope_of_press() {
+    let config = indoc! {"
+        modmap:
+          - remap:
+              CapsLock:
+                held: Control_L
+                alone: Esc
+              a: b
+    "};
+    let mut config: Config = serde_yaml::from_str(config).unwrap();
+    config.keymap_table = build_keymap_table(&config.keymap);
+    let current_pid = Rc::new(Cell::new(Some(std::process::id())));
+    let client = FocusClient {
+        current_pid: current_pid.clone(),
+    };
+    let timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::empty()).unwrap();
+    let mut event_handler = EventHandler::new(
+        timer,
+        "default",
+        Duration::from_micros(0),
+        WMClient::new("focus", Box::new(client)),
+        ProcCache::new(),
+    );
+
+    let actions = event_handler
+        .on_events(
+            &vec![
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_CAPSLOCK, KeyValue::Press)),
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+            ],
+            &config,
+        )
+        .unwrap();
+    assert_eq!(
+        format!("{:?}", actions),
+        format!(
+            "{:?}",
+            vec![
+                Action::KeyEvent(KeyEvent::new(Key::KEY_LEFTCTRL, KeyValue::Press)),
+                Action::KeyEvent(KeyEvent::new(Key::KEY_B, KeyValue::Press)),
+            ]
+        )
+    );
+
+    // The focus moves to a window outside of the command before the keys are released
+    current_pid.set(Some(std::os::unix::process::parent_id()));
+    let actions = event_handler
+        .on_events(
+            &vec![
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Release)),
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_CAPSLOCK, KeyValue::Release)),
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+            ],
+            &config,
+        )
+        .unwrap();
+    assert_eq!(
+        format!("{:?}", actions),
+        format!(
+            "{:?}",
+            vec![
+                Action::KeyEvent(KeyEvent::new(Key::KEY_B, KeyValue::Release)),
+                Action::KeyEvent(KeyEvent::new(Key::KEY_LEFTCTRL, KeyValue::Release)),
+                Action::KeyEvent(KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+            ]
+        )
+    );
+}
+
+#[test]
+fn test_flush_held_keys_on_scope_change() {
+    let config = indoc! {"
+        modmap:
+          - remap:
+              CapsLock:
+                held: Control_L
+                alone: Esc
+              a: b
+    "};
+    let mut config: Config = serde_yaml::from_str(config).unwrap();
+    config.keymap_table = build_keymap_table(&config.keymap);
+    let current_pid = Rc::new(Cell::new(Some(std::process::id())));
+    let client = FocusClient {
+        current_pid: current_pid.clone(),
+    };
+    let timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::empty()).unwrap();
+    let mut event_handler = EventHandler::new(
+        timer,
+        "default",
+        Duration::from_micros(0),
+        WMClient::new("focus", Box::new(client)),
+        ProcCache::new(),
+    );
+
+    // A multi-purpose key still waiting for its alone timeout is dropped when a key is pressed in another scope
+    let actions = event_handler
+        .on_events(
+            &vec![Event::KeyEvent(
+                get_input_device_info(),
+                KeyEvent::new(Key::KEY_CAPSLOCK, KeyValue::Press),
+            )],
+            &config,
+        )
+        .unwrap();
+    assert_eq!(format!("{:?}", actions), "[]");
+    current_pid.set(Some(std::os::unix::process::parent_id()));
+    let actions = event_handler
+        .on_events(
+            &vec![
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_CAPSLOCK, KeyValue::Repeat)),
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_CAPSLOCK, KeyValue::Release)),
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Release)),
+            ],
+            &config,
+        )
+        .unwrap();
+    assert_eq!(
+        format!("{:?}", actions),
+        format!(
+            "{:?}",
+            vec![
+                Action::KeyEvent(KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+                Action::KeyEvent(KeyEvent::new(Key::KEY_A, KeyValue::Release)),
+            ]
+        )
+    );
+
+    // Held keys remapped in the previous scope are released
+    current_pid.set(Some(std::process::id()));
+    let actions = event_handler
+        .on_events(
+            &vec![
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_CAPSLOCK, KeyValue::Press)),
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+            ],
+            &config,
+        )
+        .unwrap();
+    assert_eq!(
+        format!("{:?}", actions),
+        format!(
+            "{:?}",
+            vec![
+                Action::KeyEvent(KeyEvent::new(Key::KEY_LEFTCTRL, KeyValue::Press)),
+                Action::KeyEvent(KeyEvent::new(Key::KEY_B, KeyValue::Press)),
+            ]
+        )
+    );
+    current_pid.set(Some(std::os::unix::process::parent_id()));
+    let actions = event_handler
+        .on_events(
+            &vec![
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_X, KeyValue::Press)),
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Release)),
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_CAPSLOCK, KeyValue::Release)),
+            ],
+            &config,
+        )
+        .unwrap();
+    assert_eq!(
+        format!("{:?}", actions),
+        format!(
+            "{:?}",
+            vec![
+                Action::KeyEvent(KeyEvent::new(Key::KEY_B, KeyValue::Release)),
+                Action::KeyEvent(KeyEvent::new(Key::KEY_LEFTCTRL, KeyValue::Release)),
+                Action::KeyEvent(KeyEvent::new(Key::KEY_X, KeyValue::Press)),
+            ]
+        )
+    );
+}
+
+#[test]
+fn test_focus_hooks() {
+    let config = indoc! {"
+        focus_hooks:
+          - on_focus: { set_mode: insert }
+            on_blur: { set_mode: default }
+        modmap:
+          - remap:
+              a: b
+            mode: insert
+            scope: global
+    "};
+    let mut config: Config = serde_yaml::from_str(config).unwrap();
+    config.keymap_table = build_keymap_table(&config.keymap);
+    let current_pid = Rc::new(Cell::new(Some(std::process::id())));
+    let client = FocusClient {
+        current_pid: current_pid.clone(),
+    };
+    let timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::empty()).unwrap();
+    let mut event_handler = EventHandler::new(
+        timer,
+        "default",
+        Duration::from_micros(0),
+        WMClient::new("focus", Box::new(client)),
+        ProcCache::new(),
+    );
+
+    // The focus moves to a window of the command
+    let actions = event_handler
+        .on_events(
+            &vec![
+                Event::FocusChange,
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Release)),
+            ],
+            &config,
+        )
+        .unwrap();
+    assert_eq!(
+        format!("{:?}", actions),
+        format!(
+            "{:?}",
+            vec![
+                Action::KeyEvent(KeyEvent::new(Key::KEY_B, KeyValue::Press)),
+                Action::KeyEvent(KeyEvent::new(Key::KEY_B, KeyValue::Release)),
+            ]
+        )
+    );
+
+    // The focus leaves it, noticed on the next key press
+    current_pid.set(Some(std::os::unix::process::parent_id()));
+    let actions = event_handler
+        .on_events(
+            &vec![Event::KeyEvent(
+                get_input_device_info(),
+                KeyEvent::new(Key::KEY_A, KeyValue::Press),
+            )],
+            &config,
+        )
+        .unwrap();
+    assert_eq!(
+        format!("{:?}", actions),
+        format!("{:?}", vec![Action::KeyEvent(KeyEvent::new(Key::KEY_A, KeyValue::Press))])
+    );
+}
+
+#[test]
+fn test_focus_hooks_on_config_reload() {
+    let config = indoc! {"
+        focus_hooks:
+          - on_focus: { set_mode: insert }
+    "};
+    let reloaded_config = indoc! {"
+        focus_hooks:
+          - on_focus: { set_mode: other }
+        modmap:
+          - remap:
+              a: b
+            mode: other
+            scope: global
+    "};
+    let mut config: Config = serde_yaml::from_str(config).unwrap();
+    config.keymap_table = build_keymap_table(&config.keymap);
+    let mut reloaded_config: Config = serde_yaml::from_str(reloaded_config).unwrap();
+    reloaded_config.keymap_table = build_keymap_table(&reloaded_config.keymap);
+    let client = FocusClient {
+        current_pid: Rc::new(Cell::new(Some(std::process::id()))),
+    };
+    let timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::empty()).unwrap();
+    let mut event_handler = EventHandler::new(
+        timer,
+        "default",
+        Duration::from_micros(0),
+        WMClient::new("focus", Box::new(client)),
+        ProcCache::new(),
+    );
+    event_handler.on_events(&vec![Event::FocusChange], &config).unwrap();
+
+    // The hook of the reloaded config runs although the focus stays on a window of the command
+    let actions = event_handler
+        .on_events(
+            &vec![
+                Event::ConfigReload,
+                Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+            ],
+            &reloaded_config,
+        )
+        .unwrap();
+    assert_eq!(
+        format!("{:?}", actions),
+        format!("{:?}", vec![Action::KeyEvent(KeyEvent::new(Key::KEY_B, KeyValue::Press))])
+    );
+}
+
+#[test]
+fn test_window_properties() {
+    let config = indoc! {"
+        modmap:
+          - remap:
+              a: b
+            fullscreen: false
+          - remap:
+              c: d
+            workspace:
+              only: ['2', '3']
+            output:
+              not: /^HDMI/
+          - remap:
+              e: f
+            marks:
+              only: scratch
+    "};
+    let events = || {
+        vec![
+            Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+            Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_C, KeyValue::Press)),
+            Event::KeyEvent(get_input_device_info(), KeyEvent::new(Key::KEY_E, KeyValue::Press)),
+        ]
+    };
+    let properties = WindowProperties {
+        workspace: Some("2".to_string()),
+        output: Some("eDP-1".to_string()),
+        fullscreen: false,
+        floating: false,
+        marks: vec!["todo".to_string(), "scratch".to_string()],
+    };
+    assert_actions_with_window_properties(
+        config,
+        Some(properties.clone()),
+        events(),
+        vec![
+            Action::KeyEvent(KeyEvent::new(Key::KEY_B, KeyValue::Press)),
+            Action::KeyEvent(KeyEvent::new(Key::KEY_D, KeyValue::Press)),
+            Action::KeyEvent(KeyEvent::new(Key::KEY_F, KeyValue::Press)),
+        ],
+    );
+
+    // A fullscreen window on another output, without marks
+    assert_actions_with_window_properties(
+        config,
+        Some(WindowProperties {
+            output: Some("HDMI-A-1".to_string()),
+            fullscreen: true,
+            marks: vec![],
+            ..properties
+        }),
+        events(),
+        vec![
+            Action::KeyEvent(KeyEvent::new(Key::KEY_A, KeyValue::Press)),
+            Action::KeyEvent(KeyEvent::new(Key::KEY_C, KeyValue::Press)),
+            Action::KeyEvent(KeyEvent::new(Key::KEY_E, KeyValue::Press)),
+        ],
+    );
+
+    // Clients that don't report properties
+    assert_actions_with_window_properties(
+        config,
+        None,
+        events(),
+        vec![
+            Action::KeyEvent(KeyEvent::new(Key::KEY_B, KeyValue::Press)),
+            Action::KeyEvent(KeyEvent::new(Key::KEY_C, KeyValue::Press)),
+            Action::KeyEvent(KeyEvent::new(Key::KEY_E, KeyValue::Press)),
+        ],
+    );
+}
+
 fn assert_actions(config_yaml: &str, events: Vec<Event>, actions: Vec<Action>) {
     assert_actions_with_current_application(config_yaml, None, events, actions);
 }
@@ -786,6 +1316,59 @@ fn assert_actions_with_current_application(
     events: Vec<Event>,
     actions: Vec<Action>,
 ) {
+    // Windows of the test process are in scope since it acts as the wrapper
+    let current_pid = Some(std::process::id());
+    assert_actions_with_client(
+        config_yaml,
+        StaticClient {
+            current_application,
+            current_pid,
+            window_properties: None,
+        },
+        events,
+        actions,
+    );
+}
+
+fn assert_actions_with_current_pid(
+    config_yaml: &str,
+    current_pid: Option<u32>,
+    events: Vec<Event>,
+    actions: Vec<Action>,
+) {
+    let current_application = None;
+    assert_actions_with_client(
+        config_yaml,
+        StaticClient {
+            current_application,
+            current_pid,
+            window_properties: None,
+        },
+        events,
+        actions,
+    );
+}
+
+fn assert_actions_with_window_properties(
+    config_yaml: &str,
+    window_properties: Option<WindowProperties>,
+    events: Vec<Event>,
+    actions: Vec<Action>,
+) {
+    let current_pid = Some(std::process::id());
+    assert_actions_with_client(
+        config_yaml,
+        StaticClient {
+            current_application: None,
+            current_pid,
+            window_properties,
+        },
+        events,
+        actions,
+    );
+}
+
+fn assert_actions_with_client(config_yaml: &str, client: StaticClient, events: Vec<Event>, actions: Vec<Action>) {
     let timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::empty()).unwrap();
     let mut config: Config = serde_yaml::from_str(config_yaml).unwrap();
     config.keymap_table = build_keymap_table(&config.keymap);
@@ -793,7 +1376,8 @@ fn assert_actions_with_current_application(
         timer,
         "default",
         Duration::from_micros(0),
-        WMClient::new("static", Box::new(StaticClient { current_application })),
+        WMClient::new("static", Box::new(client)),
+        ProcCache::new(),
     );
     let mut actual: Vec<Action> = vec![];
 